@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ncmpwn::qmcdump::{decrypt_data, get_mask, KEY};
+
+const LEN: usize = 16 << 20;
+
+/// Decrypt byte by byte with `get_mask`, as done before the mask table.
+fn decrypt_data_bytewise(buf: &mut [u8], offset: usize) {
+    for (index, byte) in buf.iter_mut().enumerate() {
+        *byte ^= get_mask(offset + index, &KEY);
+    }
+}
+
+fn bench_qmc(c: &mut Criterion) {
+    let data: Vec<u8> = (0..LEN).map(|n| (n * 13) as u8).collect();
+
+    let mut group = c.benchmark_group("qmc");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.sample_size(20);
+
+    group.bench_function("bytewise", |b| {
+        b.iter_batched_ref(
+            || data.clone(),
+            |buf| decrypt_data_bytewise(buf, 0),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("mask table", |b| {
+        b.iter_batched_ref(
+            || data.clone(),
+            |buf| decrypt_data(buf, 0),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_qmc);
+criterion_main!(benches);
@@ -113,9 +113,9 @@ impl Component for App {
                                     let task = gloo_file::callbacks::read_as_bytes(&file, move |res| {
                                         let res = m! {
                                             buf <- res.map_err(|e| DumpError::IO(e.to_string()));
-                                            res <- dump_api::decrypt_ncm(&buf);
+                                            res <- dump_api::decrypt_ncm(buf);
                                             let (info, image, data) = res;
-                                            format <- match dump_api::guess_from_ncm_info(&info) {
+                                            format <- match dump_api::sniff_or(&data, dump_api::guess_from_ncm_info(&info)) {
                                                 MediaFormat::Unknown | MediaFormat::Unsupported => Err(DumpError::FormatError),
                                                 f => Ok(f),
                                             };
@@ -150,13 +150,14 @@ impl Component for App {
                                 // ===========
                                 // === QMC ===
                                 // ===========
-                                "qmc3" | "qmcflac" => {
+                                _ if !matches!(qmc_format, MediaFormat::Unsupported) => {
+                                    let filepath = filepath.clone();
                                     let task = gloo_file::callbacks::read_as_bytes(&file, move |res| {
                                         let res = m! {
                                             buf <- res.map_err(|e| DumpError::IO(e.to_string()));
-                                            res <- dump_api::decrypt_qmc(&buf);
-                                            let (_, data) = res;
-                                            return (data, qmc_format);
+                                            res <- dump_api::decrypt_qmc(buf, &filepath);
+                                            let (format, data) = res;
+                                            return (data, format);
                                         };
 
                                         match res {
@@ -176,7 +177,33 @@ impl Component for App {
                                     task_table.insert(uuid, task);
                                     Ok(())
                                 }
-                                _ => Err(DumpError::FormatError),
+                                // ===========
+                                // === Any ===
+                                // ===========
+                                _ => {
+                                    let task = gloo_file::callbacks::read_as_bytes(&file, move |res| {
+                                        let res = m! {
+                                            buf <- res.map_err(|e| DumpError::IO(e.to_string()));
+                                            dump_api::decrypt_any(&buf, &filename)
+                                        };
+
+                                        match res {
+                                            Ok((format, info, data)) => {
+                                                let orig = filename.clone();
+                                                let mut filename = filename;
+                                                filename.push_str(media_mime_to_ext(format));
+                                                let decrypted_file = Box::new(DecryptedFile::new(&orig, &filename, None, &data, info));
+                                                link.send_message(Msg::Finish(uuid, decrypted_file))
+                                            }
+                                            Err(e) => {
+                                                link.send_message(Msg::Error(uuid, e.to_string()));
+                                            }
+                                        }
+                                    });
+
+                                    task_table.insert(uuid, task);
+                                    Ok(())
+                                }
                             }
                         }
                     };
@@ -3,8 +3,8 @@ use image::ImageFormat;
 pub use ncmpwn::ncmdump::MediaFormat;
 pub use ncmpwn::ncmdump::{error::DumpResult, error::Error as DumpError};
 use ncmpwn::{
-    ncmdump::{NcmDump, NcmInfo},
-    qmcdump::QmcDump,
+    ncmdump::{NcmHeader, NcmInfo},
+    qmcdump::{self, registry, Qmc2Dump, QmcCipher, TmDump},
 };
 use std::{
     io::{Cursor, Read},
@@ -12,46 +12,69 @@ use std::{
 };
 
 pub fn guess_from_qmc_ext(path: &Path) -> MediaFormat {
-    match path.extension() {
-        None => MediaFormat::Unsupported,
-        Some(ext) => match ext.to_str() {
-            None => MediaFormat::Unsupported,
-            Some(s) => match s {
-                "qmcflac" => MediaFormat::fLaC,
-                "qmc3" => MediaFormat::ID3v2,
-                _ => MediaFormat::Unsupported,
-            },
-        },
-    }
+    registry::guess_from_path(path)
+        .map(|t| t.format)
+        .unwrap_or(MediaFormat::Unsupported)
 }
 
 pub fn guess_from_ncm_info(info: &NcmInfo) -> MediaFormat {
     info.format.as_str().into()
 }
 
-pub fn decrypt_qmc(source: &[u8]) -> DumpResult<(MediaFormat, Vec<u8>)> {
-    let reader = Cursor::new(source);
-    let mut reader = QmcDump::from_reader(reader);
+/// The container found in the decrypted audio, or `fallback` if there is none.
+pub fn sniff_or(data: &[u8], fallback: MediaFormat) -> MediaFormat {
+    match MediaFormat::sniff(data) {
+        MediaFormat::Unknown => fallback,
+        format => format,
+    }
+}
+
+pub fn decrypt_qmc(mut source: Vec<u8>, path: &Path) -> DumpResult<(MediaFormat, Vec<u8>)> {
+    let qmc_type = registry::guess_from_path(path).ok_or(DumpError::FormatError)?;
+    let reader = Cursor::new(&source[..]);
+    let mut reader: Box<dyn Read + '_> = match qmc_type.cipher {
+        QmcCipher::Qmc2 => Box::new(Qmc2Dump::from_reader(reader)?),
+        QmcCipher::TmHeader => Box::new(TmDump::from_reader(reader)),
+        // Decrypted in place, without a second buffer
+        QmcCipher::Static | QmcCipher::Plain => {
+            if qmc_type.cipher == QmcCipher::Static {
+                qmcdump::decrypt_data(&mut source, 0);
+            }
+            return Ok((sniff_or(&source, qmc_type.format), source));
+        }
+    };
 
     let mut res = vec![];
     match reader.read_to_end(&mut res) {
-        Ok(_) => Ok((reader.get_format(), res)),
+        Ok(_) => Ok((sniff_or(&res, qmc_type.format), res)),
         Err(e) => Err(e.into()),
     }
 }
 
-pub fn decrypt_ncm(source: &[u8]) -> DumpResult<(NcmInfo, Vec<u8>, Vec<u8>)> {
-    let reader = Cursor::new(source);
-    let mut reader = NcmDump::from_reader(reader)?;
-
-    let info = reader.get_info()?;
-    let image = reader.get_image()?;
+/// Decrypt a file of any type `ncmpwn` can detect on its own.
+pub fn decrypt_any(
+    source: &[u8],
+    filename: &str,
+) -> DumpResult<(MediaFormat, Option<NcmInfo>, Vec<u8>)> {
+    let mut reader = ncmpwn::open_any(Cursor::new(source), Some(filename))?;
+    let format = reader.format()?;
+    let info = reader.info()?;
 
     reader.move_to_start()?;
-    let buf = vec![];
-    // reader.read_to_end(&mut buf)?;
-    let mut cursor = Cursor::new(buf);
-    reader.write_with_tag(&mut cursor)?;
+    let mut res = vec![];
+    reader.read_to_end(&mut res)?;
+    Ok((format, info, res))
+}
+
+pub fn decrypt_ncm(mut source: Vec<u8>) -> DumpResult<(NcmInfo, Vec<u8>, Vec<u8>)> {
+    let header = NcmHeader::parse(&source)?;
+    let info = header.info(&source)?;
+    let image = source[header.image_range()].to_vec();
+
+    // The audio is decrypted in place, and only copied once by the tagging
+    header.decrypt_audio(&mut source);
+    let mut cursor = Cursor::new(Vec::with_capacity(source.len()));
+    header.write_with_tag(&source, &mut cursor)?;
 
     Ok((info, image, cursor.into_inner()))
 }
@@ -60,6 +83,14 @@ pub fn media_mime_to_ext(format: MediaFormat) -> &'static str {
     match format {
         MediaFormat::fLaC => ".flac",
         MediaFormat::ID3v2 => ".mp3",
+        MediaFormat::M4A => ".m4a",
+        MediaFormat::WAV => ".wav",
+        MediaFormat::Ogg => ".ogg",
+        MediaFormat::Opus => ".opus",
+        MediaFormat::AAC => ".aac",
+        MediaFormat::APE => ".ape",
+        MediaFormat::WavPack => ".wv",
+        MediaFormat::DSF => ".dsf",
         _ => "",
     }
 }
@@ -92,6 +123,11 @@ pub fn data_to_element(format: MediaFormat, data: &str) -> String {
     match format {
         MediaFormat::fLaC => format!("data:audio/flac;base64,{}", data),
         MediaFormat::ID3v2 => format!("data:audio/mp3;base64,{}", data),
+        MediaFormat::M4A => format!("data:audio/mp4;base64,{}", data),
+        MediaFormat::WAV => format!("data:audio/wav;base64,{}", data),
+        MediaFormat::Ogg => format!("data:audio/ogg;base64,{}", data),
+        MediaFormat::Opus => format!("data:audio/ogg;base64,{}", data),
+        MediaFormat::AAC => format!("data:audio/aac;base64,{}", data),
         _ => "".to_owned(),
     }
 }
@@ -1,6 +1,15 @@
 use clap::Parser;
 use do_notation::m;
-use ncmpwn::{ncmdump::NcmDump, qmcdump::QmcDump};
+use ncmpwn::{
+    jooxdump::JooxDump,
+    kgmdump::{KggKeyStore, KgmDump},
+    kwmdump::KwmDump,
+    mmkv::MmkvKeyStore,
+    ncmdump::{NcmCacheDump, NcmDump},
+    qmcdump::{self, Qmc2Dump, QmcCipher},
+    xmdump::XmDump,
+    Decryptor,
+};
 use thiserror::Error;
 #[cfg(feature = "log")]
 #[macro_use]
@@ -11,27 +20,61 @@ use std::env;
 use std::io;
 use std::iter::Iterator;
 use std::path;
+use std::process;
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
 
 #[derive(Debug, Parser)]
 struct CliArgs {
+    /// Files of any supported type, detected by content or extension
+    pub files: Vec<path::PathBuf>,
     #[arg(short, long)]
     pub ncm: Vec<path::PathBuf>,
     #[arg(short, long)]
     pub qmc: Vec<path::PathBuf>,
+    #[arg(short, long)]
+    pub kgm: Vec<path::PathBuf>,
+    #[arg(long)]
+    pub kwm: Vec<path::PathBuf>,
+    #[arg(short, long)]
+    pub xmly: Vec<path::PathBuf>,
+    #[arg(long)]
+    pub xm: Vec<path::PathBuf>,
+    /// NetEase Cloud Music cache files (.uc!)
+    #[arg(long)]
+    pub uc: Vec<path::PathBuf>,
+    /// Joox files (.ofl_en)
+    #[arg(long, requires = "joox_uuid")]
+    pub joox: Vec<path::PathBuf>,
 
     /// Number of workers
     #[arg(short, long, default_value_t = 1)]
     pub worker: u8,
 
-    /// Add tag for ncm files
+    /// Add tag for ncm and .uc! files
     #[arg(short, long, default_value_t = false)]
     pub tag: bool,
 
     /// Output dir (default: PWD)
     #[arg(short, long)]
     pub output: Option<path::PathBuf>,
+
+    /// QQ Music MMKV file to look up keys of .mflac/.mgg files in
+    #[arg(long)]
+    pub mmkv: Option<path::PathBuf>,
+
+    /// Device value the MMKV file is encrypted with
+    #[arg(long, requires = "mmkv")]
+    pub mmkv_key: Option<String>,
+
+    /// Kugou's KGMusicV3.db to look up keys of .kgg files in
+    #[arg(long)]
+    pub kgg_db: Option<path::PathBuf>,
+
+    /// UUID of the device the Joox files were downloaded on
+    #[arg(long)]
+    pub joox_uuid: Option<String>,
 }
 
 macro_rules! send_job {
@@ -52,6 +95,13 @@ macro_rules! send {
 enum Job {
     Ncm(path::PathBuf),
     Qmc(path::PathBuf),
+    Kgm(path::PathBuf),
+    Kwm(path::PathBuf),
+    Xmly(path::PathBuf),
+    Xm(path::PathBuf),
+    Uc(path::PathBuf),
+    Joox(path::PathBuf),
+    Any(path::PathBuf),
     End,
 }
 
@@ -64,10 +114,30 @@ fn main() {
         .output
         .unwrap_or_else(|| env::current_dir().expect("Cannot get PWD"));
     let tag = args.tag;
+    let keys = args
+        .mmkv
+        .as_ref()
+        .map(|p| match MmkvKeyStore::open(p, args.mmkv_key.as_deref()) {
+            Ok(keys) => Arc::new(keys),
+            Err(e) => {
+                error!("{:?}: {}", p, e);
+                process::exit(1);
+            }
+        });
+    let kgg_keys = args.kgg_db.as_ref().map(|p| match KggKeyStore::open(p) {
+        Ok(keys) => Arc::new(keys),
+        Err(e) => {
+            error!("{:?}: {}", p, e);
+            process::exit(1);
+        }
+    });
     for _ in 0..args.worker {
         let (tx, rx) = mpsc::channel();
         txs.push(tx);
         let output_dir = output_dir.clone();
+        let keys = keys.clone();
+        let kgg_keys = kgg_keys.clone();
+        let joox_uuid = args.joox_uuid.clone();
 
         let handle = thread::spawn(move || loop {
             match rx.recv().unwrap() {
@@ -78,18 +148,46 @@ fn main() {
                     ncmdump(&fp, &output_dir, tag);
                 }
                 Job::Qmc(fp) => {
-                    qmcdump(&fp, &output_dir);
+                    qmcdump(&fp, &output_dir, keys.as_deref());
+                }
+                Job::Kgm(fp) => {
+                    kgmdump(&fp, &output_dir, kgg_keys.as_deref());
+                }
+                Job::Kwm(fp) => {
+                    kwmdump(&fp, &output_dir);
+                }
+                Job::Xmly(fp) => {
+                    anydump(&fp, &output_dir, false);
+                }
+                Job::Xm(fp) => {
+                    xmdump(&fp, &output_dir);
+                }
+                Job::Uc(fp) => {
+                    ucdump(&fp, &output_dir, tag);
+                }
+                Job::Any(fp) => {
+                    anydump(&fp, &output_dir, tag);
+                }
+                Job::Joox(fp) => {
+                    jooxdump(&fp, &output_dir, joox_uuid.as_deref().unwrap_or_default());
                 }
             }
         });
         handles.push(handle);
     }
 
+    send_job!(txs.clone(), args.files, Job::Any);
     send_job!(txs.clone(), args.ncm, Job::Ncm);
     send_job!(txs.clone(), args.qmc, Job::Qmc);
+    send_job!(txs.clone(), args.kgm, Job::Kgm);
+    send_job!(txs.clone(), args.kwm, Job::Kwm);
+    send_job!(txs.clone(), args.xmly, Job::Xmly);
+    send_job!(txs.clone(), args.xm, Job::Xm);
+    send_job!(txs.clone(), args.uc, Job::Uc);
+    send_job!(txs.clone(), args.joox, Job::Joox);
     send!(
         txs.clone(),
-        std::iter::repeat(Job::End).take(args.worker as usize)
+        std::iter::repeat_n(Job::End, args.worker as usize)
     );
 
     for handle in handles {
@@ -97,6 +195,72 @@ fn main() {
     }
 }
 
+fn anydump(input: &path::Path, output_dir: &path::Path, add_tag: bool) {
+    let res: Result<(), CliError> = m! {
+        basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
+        basename <- basename.to_str().ok_or(CliError::BaseNameError);
+        reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
+        dump <- ncmpwn::open_any(reader, input.to_str()).map_err(|e| CliError::Other(e.to_string()));
+        let mut dump = dump;
+        let () = warn_mismatch(input, dump.as_mut());
+        format <- dump.format().map_err(|e| CliError::Other(e.to_string()));
+        ext <- extension(format);
+        let output_file = format!("{basename}.{ext}");
+        let mut output_dir = output_dir.to_owned();
+        let _ = output_dir.push(output_file);
+        write <- std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_dir)
+            .map_err(|_| CliError::WriteError(output_dir.clone()));
+        let mut write = write;
+        let has_info = matches!(dump.info(), Ok(Some(_)));
+
+        if add_tag && has_info {
+            ncmpwn::decryptor::write_with_tag(dump.as_mut(), &mut write)
+                .map_err(|_| CliError::WriteError(output_dir))
+        } else {
+            dump.move_to_start()
+                .and_then(|_| io::copy(&mut dump, &mut write))
+                .map_err(|_| CliError::WriteError(output_dir))
+                .map(|_| ())
+        }
+    };
+
+    if let Err(e) = res {
+        error!("{:?}: {}", input, e);
+    }
+}
+
+fn extension(format: ncmpwn::MediaFormat) -> Result<&'static str, CliError> {
+    match format {
+        ncmpwn::MediaFormat::fLaC => Ok("flac"),
+        ncmpwn::MediaFormat::ID3v2 => Ok("mp3"),
+        ncmpwn::MediaFormat::M4A => Ok("m4a"),
+        ncmpwn::MediaFormat::WAV => Ok("wav"),
+        ncmpwn::MediaFormat::Ogg => Ok("ogg"),
+        ncmpwn::MediaFormat::Opus => Ok("opus"),
+        ncmpwn::MediaFormat::AAC => Ok("aac"),
+        ncmpwn::MediaFormat::APE => Ok("ape"),
+        ncmpwn::MediaFormat::WavPack => Ok("wv"),
+        ncmpwn::MediaFormat::DSF => Ok("dsf"),
+        _ => Err(CliError::UnsupportedFormat),
+    }
+}
+
+/// Tell when the audio is not the container its file claims.
+fn warn_mismatch(input: &path::Path, dump: &mut dyn Decryptor) {
+    match dump.format_mismatch() {
+        Ok(Some(mismatch)) => warn!(
+            "{:?}: claims to be {:?} but holds {:?}",
+            input, mismatch.declared, mismatch.sniffed
+        ),
+        Ok(None) => {}
+        Err(e) => warn!("{:?}: cannot check the audio format: {}", input, e),
+    }
+}
+
 fn ncmdump(input: &path::Path, output_dir: &path::Path, add_tag: bool) {
     let res: Result<(), CliError> = m! {
         basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
@@ -104,17 +268,15 @@ fn ncmdump(input: &path::Path, output_dir: &path::Path, add_tag: bool) {
         reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
         dump <- NcmDump::from_reader(reader).map_err(|e| CliError::Other(e.to_string()));
         let mut dump = dump;
-        info <- dump.get_info().map_err(|e| CliError::Other(e.to_string()));
-        ext <- match ncmpwn::ncmdump::MediaFormat::from(info.format.as_str()) {
-            ncmpwn::ncmdump::MediaFormat::fLaC => Ok("flac"),
-            ncmpwn::ncmdump::MediaFormat::ID3v2 => Ok("mp3"),
-            _ => Err(CliError::UnsupportedFormat),
-        };
+        let () = warn_mismatch(input, &mut dump);
+        format <- dump.get_format().map_err(|e| CliError::Other(e.to_string()));
+        ext <- extension(format);
         let output_file = format!("{basename}.{ext}");
         let mut output_dir = output_dir.to_owned();
         let _ = output_dir.push(output_file);
         write <- std::fs::File::options()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(&output_dir)
             .map_err(|_| CliError::WriteError(output_dir.clone()));
@@ -128,28 +290,69 @@ fn ncmdump(input: &path::Path, output_dir: &path::Path, add_tag: bool) {
     };
 
     if let Err(e) = res {
-        error!("{:?}: {}", input, e.to_string());
+        error!("{:?}: {}", input, e);
     }
 }
 
-fn qmcdump(input: &path::Path, output_dir: &path::Path) {
+fn ucdump(input: &path::Path, output_dir: &path::Path, add_tag: bool) {
     let res: Result<(), CliError> = m! {
         basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
         basename <- basename.to_str().ok_or(CliError::BaseNameError);
-        ext <- input.extension().ok_or(CliError::NoFormat);
-        ext <- ext.to_str().ok_or(CliError::NoFormat);
-        ext <- match ext {
-            "qmc3" => Ok("mp3"),
-            "qmcflac" => Ok("flac"),
-            _ => Err(CliError::UnsupportedFormat),
-        };
+        dump <- NcmCacheDump::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
+        let mut dump = dump;
+        format <- dump.get_format().map_err(|e| CliError::Other(e.to_string()));
+        ext <- extension(format);
         let output_file = format!("{basename}.{ext}");
+        let mut output_dir = output_dir.to_owned();
+        let _ = output_dir.push(output_file);
+        write <- std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_dir)
+            .map_err(|_| CliError::WriteError(output_dir.clone()));
+        let mut write = write;
+
+        if add_tag && dump.get_info().is_ok() {
+            dump.write_with_tag(&mut write).map_err(|_| CliError::WriteError(output_dir))
+        } else {
+            dump.write_to(&mut write).map_err(|_| CliError::WriteError(output_dir))
+        }
+    };
+
+    if let Err(e) = res {
+        error!("{:?}: {}", input, e);
+    }
+}
+
+fn qmcdump(input: &path::Path, output_dir: &path::Path, keys: Option<&MmkvKeyStore>) {
+    let res: Result<(), CliError> = m! {
+        basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
+        basename <- basename.to_str().ok_or(CliError::BaseNameError);
         reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
-        let mut dump = QmcDump::from_reader(reader);
+        let qmc_type = qmcdump::registry::guess_from_path(input);
+        let ekey = keys.and_then(|k| k.get(input));
+        dump <- match (qmc_type, ekey) {
+            // A key from the MMKV file takes over the one in the trailer
+            (Some(qmc_type), Some(ekey)) if qmc_type.cipher == QmcCipher::Qmc2 => {
+                Qmc2Dump::from_ekey(reader, ekey).map(|mut d| {
+                    d.set_format(qmc_type.format);
+                    Box::new(d) as Box<dyn Decryptor>
+                })
+            }
+            _ => ncmpwn::open_any(reader, input.to_str()),
+        }
+        .map_err(|e| CliError::Other(e.to_string()));
+        let mut dump = dump;
+        let () = warn_mismatch(input, dump.as_mut());
+        format <- dump.format().map_err(|e| CliError::Other(e.to_string()));
+        ext <- extension(format);
+        let output_file = format!("{basename}.{ext}");
         let mut output_dir = output_dir.to_owned();
         let _ = output_dir.push(output_file);
         write <- std::fs::File::options()
             .create(true)
+            .truncate(true)
             .write(true)
             .open(&output_dir)
             .map_err(|_| CliError::WriteError(output_dir.clone()));
@@ -160,7 +363,117 @@ fn qmcdump(input: &path::Path, output_dir: &path::Path) {
     };
 
     if let Err(e) = res {
-        error!("{:?}: {}", input, e.to_string());
+        error!("{:?}: {}", input, e);
+    }
+}
+
+fn kgmdump(input: &path::Path, output_dir: &path::Path, keys: Option<&KggKeyStore>) {
+    let res: Result<(), CliError> = m! {
+        basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
+        basename <- basename.to_str().ok_or(CliError::BaseNameError);
+        reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
+        dump <- match keys {
+            Some(keys) => KgmDump::from_reader_with_keys(reader, keys),
+            None => KgmDump::from_reader(reader),
+        }
+        .map_err(|e| CliError::Other(e.to_string()));
+        let mut dump = dump;
+        format <- dump.get_format().map_err(|_| CliError::NoFormat);
+        ext <- extension(format);
+        let output_file = format!("{basename}.{ext}");
+        let mut output_dir = output_dir.to_owned();
+        let _ = output_dir.push(output_file);
+        write <- std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_dir)
+            .map_err(|_| CliError::WriteError(output_dir.clone()));
+        let mut write = write;
+        dump.write_to(&mut write).map_err(|_| CliError::WriteError(output_dir))
+    };
+
+    if let Err(e) = res {
+        error!("{:?}: {}", input, e);
+    }
+}
+
+fn kwmdump(input: &path::Path, output_dir: &path::Path) {
+    let res: Result<(), CliError> = m! {
+        basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
+        basename <- basename.to_str().ok_or(CliError::BaseNameError);
+        reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
+        dump <- KwmDump::from_reader(reader).map_err(|e| CliError::Other(e.to_string()));
+        let mut dump = dump;
+        ext <- extension(dump.get_format());
+        let output_file = format!("{basename}.{ext}");
+        let mut output_dir = output_dir.to_owned();
+        let _ = output_dir.push(output_file);
+        write <- std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_dir)
+            .map_err(|_| CliError::WriteError(output_dir.clone()));
+        let mut write = write;
+        dump.write_to(&mut write).map_err(|_| CliError::WriteError(output_dir))
+    };
+
+    if let Err(e) = res {
+        error!("{:?}: {}", input, e);
+    }
+}
+
+fn xmdump(input: &path::Path, output_dir: &path::Path) {
+    let res: Result<(), CliError> = m! {
+        basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
+        basename <- basename.to_str().ok_or(CliError::BaseNameError);
+        reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
+        dump <- XmDump::from_reader(reader).map_err(|e| CliError::Other(e.to_string()));
+        let mut dump = dump;
+        ext <- extension(dump.get_format());
+        let output_file = format!("{basename}.{ext}");
+        let mut output_dir = output_dir.to_owned();
+        let _ = output_dir.push(output_file);
+        write <- std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_dir)
+            .map_err(|_| CliError::WriteError(output_dir.clone()));
+        let mut write = write;
+        dump.write_to(&mut write).map_err(|_| CliError::WriteError(output_dir))
+    };
+
+    if let Err(e) = res {
+        error!("{:?}: {}", input, e);
+    }
+}
+
+fn jooxdump(input: &path::Path, output_dir: &path::Path, uuid: &str) {
+    let res: Result<(), CliError> = m! {
+        basename <- input.file_stem().ok_or(CliError::BaseNameError).map(|s| s.to_owned());
+        basename <- basename.to_str().ok_or(CliError::BaseNameError);
+        reader <- std::fs::File::open(input).map_err(|_| CliError::OpenError(input.to_owned()));
+        dump <- JooxDump::from_reader(reader, uuid).map_err(|e| CliError::Other(e.to_string()));
+        let mut dump = dump;
+        format <- dump.format().map_err(|_| CliError::NoFormat);
+        ext <- extension(format);
+        let output_file = format!("{basename}.{ext}");
+        let mut output_dir = output_dir.to_owned();
+        let _ = output_dir.push(output_file);
+        write <- std::fs::File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&output_dir)
+            .map_err(|_| CliError::WriteError(output_dir.clone()));
+        let mut write = write;
+        dump.write_to(&mut write).map_err(|_| CliError::WriteError(output_dir))
+    };
+
+    if let Err(e) = res {
+        error!("{:?}: {}", input, e);
     }
 }
 
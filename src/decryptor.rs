@@ -0,0 +1,218 @@
+//! A common interface over every decoder, and detection of the file type.
+
+use crate::error::{DumpResult, Error};
+use crate::ncmdump::SNIFF_LEN;
+use crate::registry::FormatRegistry;
+use crate::{MediaFormat, NcmInfo};
+use std::io::{Read, Seek, SeekFrom};
+
+/// The container a file claims to hold, and the one its audio turns out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatMismatch {
+    pub declared: MediaFormat,
+    pub sniffed: MediaFormat,
+}
+
+/// A decrypted audio stream, along with whatever the file tells about it.
+pub trait Decryptor: Read + Seek {
+    /// Container named by the metadata, header or extension of the file.
+    fn declared_format(&mut self) -> DumpResult<MediaFormat>;
+
+    /// Container found in the first bytes of the decrypted audio. The
+    /// position of the stream is kept.
+    fn sniffed_format(&mut self) -> DumpResult<MediaFormat> {
+        let pos = self.stream_position()?;
+        self.move_to_start()?;
+        let mut header = vec![];
+        Read::take(&mut *self, SNIFF_LEN as u64).read_to_end(&mut header)?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(MediaFormat::sniff(&header))
+    }
+
+    /// Container of the decrypted audio: the sniffed one, or the declared
+    /// one when sniffing finds nothing.
+    fn format(&mut self) -> DumpResult<MediaFormat> {
+        match self.sniffed_format()? {
+            MediaFormat::Unknown => self.declared_format(),
+            sniffed => Ok(sniffed),
+        }
+    }
+
+    /// The two containers, when both are known and they disagree.
+    fn format_mismatch(&mut self) -> DumpResult<Option<FormatMismatch>> {
+        let declared = self.declared_format()?;
+        let sniffed = self.sniffed_format()?;
+        Ok(
+            (declared.is_known() && sniffed.is_known() && declared != sniffed)
+                .then_some(FormatMismatch { declared, sniffed }),
+        )
+    }
+
+    /// Song info embedded in the file, if the format has any.
+    fn info(&mut self) -> DumpResult<Option<NcmInfo>> {
+        Ok(None)
+    }
+
+    /// Cover image embedded in the file, if the format has any.
+    fn cover(&mut self) -> DumpResult<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Go back to the first byte of the audio.
+    fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+/// Write the audio of `decryptor` to `writer`, tagged with its info and cover.
+#[cfg(feature = "tag")]
+pub fn write_with_tag(
+    decryptor: &mut dyn Decryptor,
+    writer: &mut (impl std::io::Write + Seek),
+) -> DumpResult<()> {
+    let info = decryptor.info()?.ok_or(Error::InfoLoadError)?;
+    let cover = decryptor.cover()?;
+    let format = decryptor.format()?;
+    decryptor.move_to_start()?;
+
+    crate::ncmdump::tag::write_with_info(decryptor, writer, &info, format, cover.as_deref())
+}
+
+/// Audio that is not encrypted at all, such as QQ Music's `.tm0`/`.tm3`.
+pub(crate) struct PlainDump<R> {
+    pub(crate) reader: R,
+    pub(crate) format: MediaFormat,
+}
+
+impl<R: Read> Read for PlainDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for PlainDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for PlainDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.format)
+    }
+}
+
+/// Open a file of any type known to the global [`FormatRegistry`], which
+/// holds every built-in format along with those registered by other crates.
+/// Detection goes by magic numbers and trailers, and by the extension of
+/// `filename_hint`.
+///
+/// Types that need a key from outside of the file (`.kgg`, Joox, QMCv2 with
+/// an MMKV key) have to be opened with their own decoders.
+pub fn open_any<'a, R: Read + Seek + 'a>(
+    reader: R,
+    filename_hint: Option<&str>,
+) -> DumpResult<Box<dyn Decryptor + 'a>> {
+    FormatRegistry::global()
+        .read()
+        .map_err(|_| Error::FormatError)?
+        .open(reader, filename_hint)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_any, Decryptor, FormatMismatch, PlainDump};
+    use crate::MediaFormat;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_sniff() {
+        let sniff = MediaFormat::sniff;
+        assert_eq!(sniff(b"fLaC\0\0\0\x22"), MediaFormat::fLaC);
+        assert_eq!(sniff(b"ID3\x04"), MediaFormat::ID3v2);
+        assert_eq!(sniff(&[0xFF, 0xFB, 0x90]), MediaFormat::ID3v2);
+        assert_eq!(sniff(&[0xFF, 0xF1, 0x50]), MediaFormat::AAC);
+        assert_eq!(sniff(b"\0\0\0\x20ftypM4A "), MediaFormat::M4A);
+        assert_eq!(sniff(b"OggS\0\x02"), MediaFormat::Ogg);
+        let mut opus = b"OggS\0\x02".to_vec();
+        opus.resize(28, 0);
+        opus.extend(b"OpusHead\x01\x02");
+        assert_eq!(sniff(&opus), MediaFormat::Opus);
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVEfmt "), MediaFormat::WAV);
+        assert_eq!(sniff(b"MAC \x96\x0f"), MediaFormat::APE);
+        assert_eq!(sniff(b"wvpk\0\0\0\0"), MediaFormat::WavPack);
+        assert_eq!(sniff(b"DSD \x1c\0\0\0"), MediaFormat::DSF);
+        assert_eq!(sniff(b"fLa"), MediaFormat::Unknown);
+    }
+
+    #[test]
+    fn test_format_mismatch() {
+        let mut dump = PlainDump {
+            reader: Cursor::new(b"fLaC\0\0\0\x22".to_vec()),
+            format: MediaFormat::ID3v2,
+        };
+        assert_eq!(dump.format().unwrap(), MediaFormat::fLaC);
+        assert_eq!(
+            dump.format_mismatch().unwrap(),
+            Some(FormatMismatch {
+                declared: MediaFormat::ID3v2,
+                sniffed: MediaFormat::fLaC,
+            })
+        );
+
+        // Nothing to sniff, so the declared format stands
+        let mut dump = PlainDump {
+            reader: Cursor::new(vec![0u8; 16]),
+            format: MediaFormat::ID3v2,
+        };
+        assert_eq!(dump.format().unwrap(), MediaFormat::ID3v2);
+        assert_eq!(dump.format_mismatch().unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_ncm() {
+        let mut dump = open_any(File::open("./tests/test.ncm").unwrap(), None).unwrap();
+        assert!(matches!(dump.format().unwrap(), MediaFormat::fLaC));
+        assert!(dump.info().unwrap().is_some());
+        assert!(dump.cover().unwrap().is_some());
+
+        dump.move_to_start().unwrap();
+        let mut magic = [0u8; 4];
+        dump.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, b"fLaC");
+    }
+
+    #[test]
+    fn test_open_by_ext() {
+        // "fLaC" under the static QMC mask
+        let file = vec![0xA5, 0x06, 0xB7, 0x89, 0, 0, 0, 0];
+        let mut dump = open_any(Cursor::new(file.clone()), Some("song.qmcflac")).unwrap();
+        assert!(matches!(dump.format().unwrap(), MediaFormat::fLaC));
+        assert!(dump.info().unwrap().is_none());
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(&res[..4], b"fLaC");
+
+        let mut dump = open_any(Cursor::new(b"ID3\x04\0\0".to_vec()), Some("a.tm3")).unwrap();
+        assert!(matches!(dump.format().unwrap(), MediaFormat::ID3v2));
+
+        assert!(open_any(Cursor::new(file.clone()), Some("song.unknown")).is_err());
+        assert!(open_any(Cursor::new(file), None).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tag")]
+    fn test_write_with_tag() {
+        let mut dump = open_any(File::open("./tests/test.ncm").unwrap(), Some("test.ncm")).unwrap();
+        let name = dump.info().unwrap().unwrap().name;
+
+        let mut output = Cursor::new(vec![]);
+        super::write_with_tag(dump.as_mut(), &mut output).unwrap();
+        output.set_position(0);
+        let tag = metaflac::Tag::read_from(&mut output).unwrap();
+        assert_eq!(tag.vorbis_comments().unwrap().title().unwrap(), &[name]);
+        assert!(tag.pictures().next().is_some());
+    }
+}
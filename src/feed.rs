@@ -0,0 +1,33 @@
+//! Push-based decoding, for data that arrives in chunks of any size.
+//!
+//! A [`Feed`] does no IO. The caller hands it bytes as they come and gets
+//! back what they turned into.
+
+use crate::error::Error;
+use crate::NcmInfo;
+use alloc::vec::Vec;
+
+pub use crate::ncmdump::feed::NcmFeeder;
+pub use crate::qmcdump::feed::QmcFeeder;
+
+#[derive(Debug)]
+pub enum Event {
+    /// The file is of the expected type and its key is ready.
+    Header,
+    Info(NcmInfo),
+    Cover(Vec<u8>),
+    /// Decrypted audio, following the previous chunk.
+    Audio(Vec<u8>),
+    /// The input ended where the file does.
+    Finished,
+    /// The file is broken. Nothing more comes after this.
+    Error(Error),
+}
+
+pub trait Feed {
+    /// Push the next bytes of the file.
+    fn feed(&mut self, data: &[u8]) -> Vec<Event>;
+
+    /// Tell that the input has ended.
+    fn finish(&mut self) -> Vec<Event>;
+}
@@ -0,0 +1,232 @@
+//! Joox `.ofl_en` files: `["E!04"][audio length: u64 BE]` followed by the
+//! audio in 1 MiB blocks, each encrypted on its own with AES-128-ECB and PKCS7
+//! padding. The key is derived from the UUID of the device that downloaded
+//! the file.
+
+use crate::decryptor::Decryptor;
+use crate::error::{DumpResult, Error};
+use crate::MediaFormat;
+use aes::Aes128;
+use cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyInit};
+use sha1::Sha1;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub(crate) const MAGIC: &[u8; 4] = b"E!04";
+const HEADER_LEN: u64 = 12;
+
+const BLOCK_LEN: usize = 0x100000;
+// A full block gains a whole block of padding
+const ENCRYPTED_BLOCK_LEN: usize = BLOCK_LEN + 0x10;
+
+const SALT: [u8; 16] = [
+    0xA4, 0x0B, 0xC8, 0x34, 0xD6, 0x95, 0xF3, 0x13, 0x23, 0x23, 0x43, 0x23, 0x54, 0x63, 0x83, 0xF3,
+];
+const ROUNDS: u32 = 1000;
+
+/// Derive the AES key from the device UUID with PBKDF2-HMAC-SHA1.
+pub fn derive_key(uuid: &str) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    pbkdf2::pbkdf2_hmac::<Sha1>(uuid.as_bytes(), &SALT, ROUNDS, &mut key);
+    key
+}
+
+pub struct JooxDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    key: [u8; 16],
+    audio_len: u64,
+    block: Vec<u8>,
+    // Index of the block in `block`, and of the block the reader is at
+    block_index: Option<usize>,
+    next_block: usize,
+}
+
+impl<R: Read> JooxDump<R> {
+    pub fn from_reader(mut reader: R, uuid: &str) -> DumpResult<Self> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| Error::FormatError)?;
+        if &header[..4] != MAGIC {
+            return Err(Error::FormatError);
+        }
+
+        let mut len_buf = [0u8; 8];
+        len_buf.copy_from_slice(&header[4..]);
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            key: derive_key(uuid),
+            audio_len: u64::from_be_bytes(len_buf),
+            block: vec![],
+            block_index: None,
+            next_block: 0,
+        })
+    }
+
+    /// Length of the decrypted audio, as stated in the header.
+    pub fn audio_len(&self) -> u64 {
+        self.audio_len
+    }
+
+    fn read_block(&mut self) -> DumpResult<()> {
+        let mut buf = Vec::with_capacity(ENCRYPTED_BLOCK_LEN);
+        self.reader
+            .by_ref()
+            .take(ENCRYPTED_BLOCK_LEN as u64)
+            .read_to_end(&mut buf)?;
+
+        self.block = if buf.is_empty() {
+            buf
+        } else {
+            Aes128::new(&self.key.into())
+                .decrypt_padded_vec_mut::<Pkcs7>(&buf)
+                .map_err(|_| Error::BlockDecryptError)?
+        };
+        self.block_index = Some(self.next_block);
+        self.next_block += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> JooxDump<R> {
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for JooxDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let index = self.cursor / BLOCK_LEN;
+        if self.block_index != Some(index) {
+            self.read_block()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+
+        let offset = self.cursor % BLOCK_LEN;
+        let remain = self.block.get(offset..).unwrap_or_default();
+        let size = buf.len().min(remain.len());
+        buf[..size].copy_from_slice(&remain[..size]);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for JooxDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => (self.cursor as u64).checked_add_signed(d),
+            SeekFrom::End(d) => self.audio_len.checked_add_signed(d),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked before the start of Joox audio",
+            )
+        })?;
+
+        let index = new_pos as usize / BLOCK_LEN;
+        if self.block_index != Some(index) {
+            let block_start = HEADER_LEN + (index * ENCRYPTED_BLOCK_LEN) as u64;
+            self.reader.seek(SeekFrom::Start(block_start))?;
+            self.block_index = None;
+            self.next_block = index;
+        }
+        self.cursor = new_pos as usize;
+        Ok(new_pos)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for JooxDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(MediaFormat::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{derive_key, JooxDump, BLOCK_LEN, MAGIC};
+    use aes::Aes128;
+    use cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyInit};
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    const UUID: &str = "0123456789abcdef0123456789abcdef";
+
+    fn build_file(audio: &[u8]) -> Vec<u8> {
+        let aes = Aes128::new(&derive_key(UUID).into());
+        let mut file = MAGIC.to_vec();
+        file.extend((audio.len() as u64).to_be_bytes());
+        for block in audio.chunks(BLOCK_LEN) {
+            file.extend(aes.clone().encrypt_padded_vec_mut::<Pkcs7>(block));
+        }
+        file
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let audio: Vec<u8> = b"fLaC"
+            .iter()
+            .copied()
+            .chain((0..BLOCK_LEN + 5000).map(|n| (n % 241) as u8))
+            .collect();
+        let mut dump = JooxDump::from_reader(Cursor::new(build_file(&audio)), UUID).unwrap();
+        assert_eq!(dump.audio_len(), audio.len() as u64);
+
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert!(res == audio);
+
+        dump.seek(SeekFrom::Start(BLOCK_LEN as u64 - 8)).unwrap();
+        let mut res = [0u8; 16];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(res[..], audio[BLOCK_LEN - 8..BLOCK_LEN + 8]);
+
+        dump.seek(SeekFrom::Start(10)).unwrap();
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(res[..], audio[10..26]);
+    }
+
+    #[test]
+    fn test_decrypt_known_answer() {
+        assert_eq!(
+            derive_key(UUID),
+            [
+                0x77, 0x69, 0x1B, 0x43, 0xD9, 0xB1, 0xAF, 0xA8, 0x87, 0x6F, 0xC8, 0xAD, 0x35, 0xD0,
+                0x24, 0x68
+            ]
+        );
+
+        // "fLaC known answer", encrypted with AES-128-ECB by OpenSSL
+        let mut file = b"E!04\0\0\0\0\0\0\0\x11".to_vec();
+        file.extend([
+            0xA1, 0x6C, 0x8B, 0xDE, 0xB6, 0xAA, 0xF3, 0x6F, 0xB3, 0xEF, 0xC9, 0x85, 0xDB, 0xEF,
+            0xAD, 0x80, 0xF1, 0x88, 0x97, 0x3A, 0x10, 0x7F, 0x06, 0x2A, 0xC3, 0x88, 0x83, 0x94,
+            0xC2, 0xA1, 0xB8, 0x95,
+        ]);
+        let mut dump = JooxDump::from_reader(Cursor::new(file), UUID).unwrap();
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, b"fLaC known answer");
+    }
+
+    #[test]
+    fn test_wrong_uuid() {
+        let file = build_file(&[0u8; 100]);
+        let mut dump = JooxDump::from_reader(Cursor::new(file), "another-uuid").unwrap();
+        let mut res = vec![];
+        assert!(dump.read_to_end(&mut res).is_err());
+
+        assert!(
+            JooxDump::from_reader(Cursor::new(b"E!03\0\0\0\0\0\0\0\0".to_vec()), UUID).is_err()
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use crate::decryptor::Decryptor;
+use crate::error::{DumpResult, Error};
+use crate::ncmdump::SNIFF_LEN;
+use crate::qmcdump::{cipher::Qmc2Cipher, decrypt_ekey};
+use crate::MediaFormat;
+use md5::{Digest, Md5};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+mod infra;
+mod sqlite;
+pub use infra::{decrypt_db, KggKeyStore};
+
+pub(crate) const KGM_MAGIC: [u8; 16] = [
+    0x7C, 0xD5, 0x32, 0xEB, 0x86, 0x02, 0x7F, 0x4B, 0xA8, 0xAF, 0xA6, 0x8E, 0x0F, 0xFF, 0x99, 0x14,
+];
+
+pub(crate) const VPR_MAGIC: [u8; 16] = [
+    0x05, 0x28, 0xBC, 0x96, 0xE9, 0xE4, 0x5A, 0x43, 0x91, 0xAA, 0xBD, 0xD0, 0x7A, 0xF5, 0x36, 0x31,
+];
+
+const VPR_MASK: [u8; 17] = [
+    0x25, 0xDF, 0xE8, 0xA6, 0x75, 0x1E, 0x75, 0x0E, 0x2F, 0x80, 0xF3, 0x2D, 0xB8, 0xB6, 0xE3, 0x11,
+    0x00,
+];
+
+const SLOT_KEY_1: [u8; 4] = [0x6C, 0x2C, 0x2F, 0x27];
+
+const HEADER_LEN: usize = 0x3C;
+const MAX_HASH_LEN: usize = 0x100;
+
+pub struct KgmDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    data_start: u64,
+    cipher: KgmCipher,
+    vpr: bool,
+    audio_hash: Option<String>,
+}
+
+enum KgmCipher {
+    V3 {
+        slot_box: [u8; 16],
+        file_box: [u8; 17],
+    },
+    // `.kgg` files reuse the QMCv2 ciphers with a key from `KGMusicV3.db`
+    V5(Qmc2Cipher),
+}
+
+impl KgmCipher {
+    fn decrypt(&mut self, buf: &mut [u8], offset: usize, vpr: bool) {
+        match self {
+            Self::V3 { slot_box, file_box } => {
+                for (index, byte) in buf.iter_mut().enumerate() {
+                    let pos = offset + index;
+                    let mut b = *byte ^ file_box[pos % file_box.len()];
+                    b ^= b << 4;
+                    b ^= slot_box[pos % slot_box.len()];
+                    b ^= collapse(pos);
+                    if vpr {
+                        b ^= VPR_MASK[pos % VPR_MASK.len()];
+                    }
+                    *byte = b;
+                }
+            }
+            Self::V5(cipher) => cipher.decrypt(buf, offset),
+        }
+    }
+}
+
+/// MD5 with the 16-bit words of the digest reversed.
+fn kugou_md5(data: &[u8]) -> [u8; 16] {
+    let digest = Md5::digest(data);
+    let mut res = [0u8; 16];
+    for i in (0..16).step_by(2) {
+        res[i] = digest[14 - i];
+        res[i + 1] = digest[15 - i];
+    }
+    res
+}
+
+fn collapse(offset: usize) -> u8 {
+    (offset as u32).to_le_bytes().iter().fold(0, |a, b| a ^ b)
+}
+
+impl<R: Read + Seek> KgmDump<R> {
+    pub fn from_reader(reader: R) -> DumpResult<Self> {
+        Self::build(reader, None)
+    }
+
+    /// Same as [`KgmDump::from_reader`], and also able to decrypt `.kgg`
+    /// files whose key is looked up in `keys`.
+    pub fn from_reader_with_keys(reader: R, keys: &KggKeyStore) -> DumpResult<Self> {
+        Self::build(reader, Some(keys))
+    }
+
+    fn build(mut reader: R, keys: Option<&KggKeyStore>) -> DumpResult<Self> {
+        let mut header = [0u8; HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| Error::FormatError)?;
+
+        let vpr = match &header[..16] {
+            m if m == KGM_MAGIC => false,
+            m if m == VPR_MAGIC => true,
+            _ => return Err(Error::FormatError),
+        };
+
+        let read_u32 = |buf: &[u8]| u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let data_start = read_u32(&header[0x10..]) as u64;
+        let version = read_u32(&header[0x14..]);
+        let slot = read_u32(&header[0x18..]);
+
+        let (cipher, audio_hash) = match (version, slot) {
+            (3, 1) => {
+                let mut file_box = [0x6B; 17];
+                file_box[..16].copy_from_slice(&kugou_md5(&header[0x2C..0x3C]));
+                let cipher = KgmCipher::V3 {
+                    slot_box: kugou_md5(&SLOT_KEY_1),
+                    file_box,
+                };
+                (cipher, None)
+            }
+            (5, _) => {
+                // [..][hash length: u32 LE at 0x44][audio hash]
+                let mut len_buf = [0u8; 4];
+                reader.seek(SeekFrom::Start(0x44))?;
+                reader.read_exact(&mut len_buf)?;
+                let hash_len = read_u32(&len_buf) as usize;
+                if hash_len > MAX_HASH_LEN {
+                    return Err(Error::FormatError);
+                }
+                let mut hash = vec![0u8; hash_len];
+                reader.read_exact(&mut hash)?;
+                let hash = String::from_utf8(hash).map_err(|_| Error::FormatError)?;
+
+                let ekey = keys.and_then(|k| k.get(&hash)).ok_or(Error::KggKeyError)?;
+                (
+                    KgmCipher::V5(Qmc2Cipher::new(decrypt_ekey(ekey)?)),
+                    Some(hash),
+                )
+            }
+            _ => return Err(Error::CryptoVersionError),
+        };
+
+        reader.seek(SeekFrom::Start(data_start))?;
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            data_start,
+            cipher,
+            vpr,
+            audio_hash,
+        })
+    }
+
+    /// Hash identifying the key of a `.kgg` file in `KGMusicV3.db`.
+    pub fn get_audio_hash(&self) -> Option<&str> {
+        self.audio_hash.as_deref()
+    }
+
+    pub fn is_vpr(&self) -> bool {
+        self.vpr
+    }
+
+    /// Guess the format from the first bytes of the audio.
+    pub fn get_format(&mut self) -> DumpResult<MediaFormat> {
+        let original_pos = self.cursor as u64;
+        let mut magic = vec![];
+        self.move_to_start()?;
+        self.by_ref()
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut magic)?;
+        self.seek(SeekFrom::Start(original_pos))?;
+
+        Ok(MediaFormat::sniff(&magic))
+    }
+
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(self.data_start))?;
+        self.cursor = 0;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for KgmDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.cipher.decrypt(&mut buf[..size], self.cursor, self.vpr);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for KgmDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => SeekFrom::Start(self.data_start + p),
+            p => p,
+        };
+        let new_pos = self.reader.seek(pos)?;
+
+        if new_pos < self.data_start {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked to KGM illegal area",
+            ));
+        }
+
+        self.cursor = (new_pos - self.data_start) as usize;
+        Ok(self.cursor as u64)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for KgmDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(MediaFormat::Unknown)
+    }
+
+    fn sniffed_format(&mut self) -> DumpResult<MediaFormat> {
+        self.get_format()
+    }
+
+    fn move_to_start(&mut self) -> std::io::Result<()> {
+        KgmDump::move_to_start(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KggKeyStore, KgmCipher, KgmDump, KGM_MAGIC, VPR_MAGIC, VPR_MASK};
+    use crate::error::Error;
+    use crate::qmcdump::{cipher::Qmc2Cipher, ekey::test::encrypt_ekey};
+    use crate::MediaFormat;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    fn build_file(magic: &[u8; 16], key: &[u8; 16], audio: &[u8]) -> Vec<u8> {
+        let mut file = magic.to_vec();
+        file.extend(0x400u32.to_le_bytes());
+        file.extend(3u32.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+        file.extend([0u8; 16]);
+        file.extend(key);
+        file.resize(0x400, 0);
+
+        let dump = KgmDump::from_reader(Cursor::new(file.clone())).unwrap();
+        let KgmCipher::V3 { slot_box, file_box } = dump.cipher else {
+            unreachable!()
+        };
+        for (pos, byte) in audio.iter().enumerate() {
+            let mut b = *byte;
+            if dump.vpr {
+                b ^= VPR_MASK[pos % VPR_MASK.len()];
+            }
+            b ^= super::collapse(pos);
+            b ^= slot_box[pos % 16];
+            b ^= b << 4;
+            b ^= file_box[pos % 17];
+            file.push(b);
+        }
+        file
+    }
+
+    fn audio() -> Vec<u8> {
+        let mut audio = b"ID3\x04".to_vec();
+        audio.extend((0..70000u32).map(|n| (n * 7 % 256) as u8));
+        audio
+    }
+
+    #[test]
+    fn test_decrypt() {
+        for magic in [KGM_MAGIC, VPR_MAGIC] {
+            let file = build_file(&magic, b"0123456789abcdef", &audio());
+            let mut dump = KgmDump::from_reader(Cursor::new(file)).unwrap();
+            assert_eq!(dump.is_vpr(), magic == VPR_MAGIC);
+
+            assert_eq!(dump.get_format().unwrap(), MediaFormat::ID3v2);
+
+            let mut res = vec![];
+            dump.read_to_end(&mut res).unwrap();
+            assert_eq!(res, audio());
+
+            dump.seek(SeekFrom::Start(65530)).unwrap();
+            let mut res = [0u8; 16];
+            dump.read_exact(&mut res).unwrap();
+            assert_eq!(res[..], audio()[65530..65546]);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_known_answer() {
+        let expected: [[u8; 16]; 4] = [
+            [
+                0xD8, 0x00, 0xEB, 0xA3, 0x0D, 0xCD, 0x73, 0xE9, 0x74, 0xBE, 0x24, 0x82, 0xCC, 0x46,
+                0x6D, 0x61,
+            ],
+            [
+                0xBB, 0x61, 0x95, 0x73, 0x6C, 0xB6, 0x69, 0x20, 0x80, 0x83, 0xA9, 0x2E, 0x34, 0xBC,
+                0x6C, 0x27,
+            ],
+            [
+                0xFD, 0xDF, 0x03, 0x05, 0x78, 0xD3, 0x06, 0xE7, 0x5B, 0x3E, 0xD7, 0xAF, 0x74, 0xF0,
+                0x8E, 0x70,
+            ],
+            [
+                0x48, 0x4C, 0x2D, 0xC5, 0x8F, 0xA7, 0x69, 0x05, 0x5F, 0x6B, 0x0F, 0x5B, 0x2A, 0xC9,
+                0x62, 0x08,
+            ],
+        ];
+        let cases = [(false, 0), (false, 0xFFF8), (true, 0), (true, 0xFFF8)];
+
+        for ((vpr, offset), expected) in cases.into_iter().zip(expected) {
+            let mut file = if vpr { VPR_MAGIC } else { KGM_MAGIC }.to_vec();
+            file.extend(0x400u32.to_le_bytes());
+            file.extend(3u32.to_le_bytes());
+            file.extend(1u32.to_le_bytes());
+            file.extend([0u8; 16]);
+            file.extend(b"0123456789abcdef");
+            file.resize(0x400, 0);
+            file.extend((0..0x10010u32).map(|n| (n * 7 % 256) as u8));
+
+            let mut dump = KgmDump::from_reader(Cursor::new(file)).unwrap();
+            dump.seek(SeekFrom::Start(offset)).unwrap();
+            let mut res = [0u8; 16];
+            dump.read_exact(&mut res).unwrap();
+            assert_eq!(res, expected, "vpr {vpr}, offset {offset:#x}");
+        }
+    }
+
+    #[test]
+    fn test_decrypt_kgg() {
+        let hash = "0123456789abcdef0123456789abcdef";
+        let key: Vec<u8> = (0..512).map(|n| (n * 17 + 3) as u8).collect();
+        let mut keys = KggKeyStore::default();
+        keys.insert(hash.into(), encrypt_ekey(&key));
+
+        let mut file = KGM_MAGIC.to_vec();
+        file.extend(0x400u32.to_le_bytes());
+        file.extend(5u32.to_le_bytes());
+        file.extend(4u32.to_le_bytes());
+        file.resize(0x44, 0);
+        file.extend((hash.len() as u32).to_le_bytes());
+        file.extend(hash.as_bytes());
+        file.resize(0x400, 0);
+        let mut data = audio();
+        Qmc2Cipher::new(key).decrypt(&mut data, 0);
+        file.extend(data);
+
+        assert!(KgmDump::from_reader(Cursor::new(file.clone())).is_err());
+        assert!(
+            KgmDump::from_reader_with_keys(Cursor::new(file.clone()), &KggKeyStore::default())
+                .is_err()
+        );
+
+        let mut dump = KgmDump::from_reader_with_keys(Cursor::new(file.clone()), &keys).unwrap();
+        assert_eq!(dump.get_audio_hash(), Some(hash));
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio());
+
+        // A hash length past the limit is a broken header, not a missing key
+        file[0x44..0x48].copy_from_slice(&0x101u32.to_le_bytes());
+        assert!(matches!(
+            KgmDump::from_reader_with_keys(Cursor::new(file), &keys),
+            Err(Error::FormatError)
+        ));
+    }
+
+    #[test]
+    fn test_bad_header() {
+        assert!(KgmDump::from_reader(Cursor::new(vec![0u8; 0x100])).is_err());
+
+        let mut file = build_file(&KGM_MAGIC, b"0123456789abcdef", b"");
+        file[0x14] = 2;
+        assert!(KgmDump::from_reader(Cursor::new(file)).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Key lookup for `.kgg` files. Kugou keeps their ekeys in the encrypted
+//! SQLite database `KGMusicV3.db`, keyed by the audio hash in the KGG header.
+
+use super::sqlite::{self, Database};
+use crate::error::{DumpResult, Error};
+use aes::Aes128;
+use cipher::{BlockDecrypt, KeyInit};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::path::Path;
+
+const PAGE_SIZE: usize = 0x400;
+
+const MASTER_KEY: [u8; 16] = [
+    0x1D, 0x61, 0x31, 0x45, 0xB2, 0x47, 0xBF, 0x7F, 0x3D, 0x18, 0x96, 0x72, 0x14, 0x4F, 0xE4, 0xBF,
+];
+
+// "sAlT"
+const SALT: u32 = 0x546C_4173;
+
+const TABLE: &str = "ShareFileItems";
+const KEY_ID_COLUMN: &str = "EncryptionKeyId";
+const KEY_COLUMN: &str = "EncryptionKey";
+
+fn page_key(page_no: u32) -> [u8; 16] {
+    let mut buf = MASTER_KEY.to_vec();
+    buf.extend(page_no.to_le_bytes());
+    buf.extend(SALT.to_le_bytes());
+    Md5::digest(&buf).into()
+}
+
+/// The IV is the MD5 of four outputs of L'Ecuyer's LCG seeded with the page number.
+fn page_iv(page_no: u32) -> [u8; 16] {
+    let mut buf = [0u8; 16];
+    let mut seed = page_no.wrapping_add(1);
+    for chunk in buf.chunks_exact_mut(4) {
+        let value = 0x9EF4u32
+            .wrapping_mul(seed % 0xCE26)
+            .wrapping_sub(0xECFu32.wrapping_mul(seed / 0xCE26));
+        seed = if value & 0x8000_0000 != 0 {
+            value.wrapping_add(0x7FFF_FF07)
+        } else {
+            value
+        };
+        chunk.copy_from_slice(&seed.to_le_bytes());
+    }
+    Md5::digest(buf).into()
+}
+
+fn decrypt_cbc(buf: &mut [u8], key: &[u8; 16], iv: &[u8; 16]) {
+    let aes = Aes128::new(key.into());
+    let mut prev = *iv;
+    for block in buf.chunks_exact_mut(16) {
+        let mut cipher_block = [0u8; 16];
+        cipher_block.copy_from_slice(block);
+        aes.decrypt_block(block.into());
+        block.iter_mut().zip(prev).for_each(|(b, p)| *b ^= p);
+        prev = cipher_block;
+    }
+}
+
+/// Decrypt a `KGMusicV3.db` into a plain SQLite database. Every 1 KiB page
+/// is AES-128-CBC encrypted with a key and an IV derived from its number.
+/// The first page keeps bytes 16..24 of the SQLite header in the clear, with
+/// the ciphertext they displaced stored at 8..16.
+pub fn decrypt_db(data: &[u8]) -> DumpResult<Vec<u8>> {
+    if sqlite::is_plain(data) {
+        return Ok(data.to_vec());
+    }
+    if data.is_empty() || !data.len().is_multiple_of(PAGE_SIZE) {
+        return Err(Error::DatabaseError);
+    }
+
+    let mut db = data.to_vec();
+    for (index, page) in db.chunks_exact_mut(PAGE_SIZE).enumerate() {
+        let page_no = index as u32 + 1;
+        let (key, iv) = (page_key(page_no), page_iv(page_no));
+
+        if page_no == 1 {
+            let mut expected = [0u8; 8];
+            expected.copy_from_slice(&page[0x10..0x18]);
+            page.copy_within(0x08..0x10, 0x10);
+            decrypt_cbc(&mut page[0x10..], &key, &iv);
+            if page[0x10..0x18] != expected {
+                return Err(Error::DatabaseError);
+            }
+            page[..0x10].copy_from_slice(b"SQLite format 3\0");
+        } else {
+            decrypt_cbc(page, &key, &iv);
+        }
+    }
+
+    Ok(db)
+}
+
+/// Mapping from the audio hash of a `.kgg` file to its ekey.
+#[derive(Debug, Default)]
+pub struct KggKeyStore {
+    keys: HashMap<String, String>,
+}
+
+impl KggKeyStore {
+    /// Load the keys from a `KGMusicV3.db`, either encrypted or already
+    /// decrypted.
+    pub fn from_bytes(data: &[u8]) -> DumpResult<Self> {
+        let db = decrypt_db(data)?;
+        let (columns, rows) = Database::new(&db)?.read_table(TABLE)?;
+        let column = |name: &str| {
+            columns
+                .iter()
+                .position(|c| c == name)
+                .ok_or(Error::DatabaseError)
+        };
+        let (id_column, key_column) = (column(KEY_ID_COLUMN)?, column(KEY_COLUMN)?);
+
+        let keys = rows
+            .iter()
+            .filter_map(|row| {
+                let id = row.get(id_column)?.as_str()?;
+                let key = row.get(key_column)?.as_str()?;
+                (!id.is_empty() && !key.is_empty()).then(|| (id.to_owned(), key.to_owned()))
+            })
+            .collect();
+
+        Ok(Self { keys })
+    }
+
+    pub fn open(path: &Path) -> DumpResult<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn insert(&mut self, audio_hash: String, ekey: String) {
+        self.keys.insert(audio_hash, ekey);
+    }
+
+    pub fn get(&self, audio_hash: &str) -> Option<&str> {
+        self.keys.get(audio_hash).map(|s| s.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{decrypt_db, page_iv, page_key, KggKeyStore, PAGE_SIZE};
+    use aes::Aes128;
+    use cipher::{BlockEncrypt, KeyInit};
+    use md5::{Digest, Md5};
+
+    fn encrypt_cbc(buf: &mut [u8], key: &[u8; 16], iv: &[u8; 16]) {
+        let aes = Aes128::new(key.into());
+        let mut prev = *iv;
+        for block in buf.chunks_exact_mut(16) {
+            block.iter_mut().zip(prev).for_each(|(b, p)| *b ^= p);
+            aes.encrypt_block(block.into());
+            prev.copy_from_slice(block);
+        }
+    }
+
+    pub(crate) fn encrypt_db(plain: &[u8]) -> Vec<u8> {
+        let mut db = plain.to_vec();
+        for (index, page) in db.chunks_exact_mut(PAGE_SIZE).enumerate() {
+            let page_no = index as u32 + 1;
+            let (key, iv) = (page_key(page_no), page_iv(page_no));
+
+            if page_no == 1 {
+                let mut header = [0u8; 8];
+                header.copy_from_slice(&page[0x10..0x18]);
+                encrypt_cbc(&mut page[0x10..], &key, &iv);
+                page.copy_within(0x10..0x18, 0x08);
+                page[0x10..0x18].copy_from_slice(&header);
+                page[..0x08].fill(0);
+            } else {
+                encrypt_cbc(page, &key, &iv);
+            }
+        }
+        db
+    }
+
+    #[test]
+    fn test_page_iv() {
+        // Schrage's method: 40692 * seed mod 2147483399
+        for page_no in [1u32, 2, 1000, 0xCE26, 0x7FFF_0000] {
+            let seed = page_no as u64 + 1;
+            let mut values = vec![];
+            let mut s = seed;
+            for _ in 0..4 {
+                s = 40692 * s % 2147483399;
+                values.extend((s as u32).to_le_bytes());
+            }
+            let expected: [u8; 16] = Md5::digest(&values).into();
+            assert_eq!(page_iv(page_no), expected);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_db() {
+        let plain = std::fs::read("./tests/kgg_infra.db").unwrap();
+        let encrypted = encrypt_db(&plain);
+        assert_ne!(encrypted[..16], plain[..16]);
+        assert_eq!(decrypt_db(&encrypted).unwrap(), plain);
+
+        let mut broken = encrypted.clone();
+        broken[0x10] ^= 1;
+        assert!(decrypt_db(&broken).is_err());
+    }
+
+    #[test]
+    fn test_key_store() {
+        let plain = std::fs::read("./tests/kgg_infra.db").unwrap();
+        for data in [plain.clone(), encrypt_db(&plain)] {
+            let store = KggKeyStore::from_bytes(&data).unwrap();
+            assert_eq!(store.len(), 39);
+
+            let key = store.get("6513270e269e0d37f2a74de452e6b438").unwrap();
+            assert_eq!(key.len(), 300);
+            assert!(key.starts_with("GJMuHbEL31Ie") && key.ends_with("R1Py4oJe2Jbm"));
+
+            let key = store.get("caab2b8d67093677e772436e3562efe9").unwrap();
+            assert_eq!(key.len(), 2000);
+            assert!(store.get("b692c7d1cdf2b4aa0785c1f8e623d713").is_none());
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! Just enough of the SQLite file format to walk a table b-tree and read its
+//! rows, so no native SQLite library is needed.
+
+use crate::error::{DumpResult, Error};
+use std::collections::HashSet;
+
+const HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Text(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+pub struct Database<'a> {
+    data: &'a [u8],
+    page_size: usize,
+    usable_size: usize,
+}
+
+pub fn is_plain(data: &[u8]) -> bool {
+    data.starts_with(HEADER)
+}
+
+fn read_varint(buf: &[u8]) -> DumpResult<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in buf.iter().enumerate().take(9) {
+        if i == 8 {
+            return Ok(((value << 8) | byte as u64, 9));
+        }
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(Error::DatabaseError)
+}
+
+fn read_be(buf: &[u8]) -> u64 {
+    buf.iter().fold(0, |v, &b| (v << 8) | b as u64)
+}
+
+impl<'a> Database<'a> {
+    pub fn new(data: &'a [u8]) -> DumpResult<Self> {
+        if !is_plain(data) || data.len() < 100 {
+            return Err(Error::DatabaseError);
+        }
+        let page_size = match read_be(&data[16..18]) {
+            1 => 65536,
+            n => n as usize,
+        };
+        let usable_size = page_size
+            .checked_sub(data[20] as usize)
+            .filter(|&u| u >= 480)
+            .ok_or(Error::DatabaseError)?;
+
+        Ok(Self {
+            data,
+            page_size,
+            usable_size,
+        })
+    }
+
+    fn page(&self, page_no: u64) -> DumpResult<&'a [u8]> {
+        let start = (page_no as usize)
+            .checked_sub(1)
+            .ok_or(Error::DatabaseError)?
+            * self.page_size;
+        self.data
+            .get(start..start + self.page_size)
+            .ok_or(Error::DatabaseError)
+    }
+
+    /// Read every row of `table`, along with the column names declared in
+    /// its `CREATE TABLE` statement.
+    pub fn read_table(&self, table: &str) -> DumpResult<(Vec<String>, Vec<Vec<Value>>)> {
+        let master = self.read_btree(1)?;
+        let (root, sql) = master
+            .iter()
+            .find_map(|row| match row.as_slice() {
+                [Value::Text(kind), Value::Text(name), _, Value::Integer(root), Value::Text(sql), ..]
+                    if kind == "table" && name.eq_ignore_ascii_case(table) =>
+                {
+                    Some((*root as u64, sql.as_str()))
+                }
+                _ => None,
+            })
+            .ok_or(Error::DatabaseError)?;
+
+        Ok((parse_columns(sql), self.read_btree(root)?))
+    }
+
+    fn read_btree(&self, root: u64) -> DumpResult<Vec<Vec<Value>>> {
+        let mut rows = vec![];
+        let mut pages = vec![root];
+        let mut visited = HashSet::new();
+
+        while let Some(page_no) = pages.pop() {
+            // A page reachable twice means the tree has a cycle
+            if !visited.insert(page_no) {
+                return Err(Error::DatabaseError);
+            }
+            let page = self.page(page_no)?;
+            let header = if page_no == 1 { &page[100..] } else { page };
+            let cell_count = read_be(&header[3..5]) as usize;
+
+            match header[0] {
+                // Interior table page: child pointers, then the right-most child
+                0x05 => {
+                    pages.push(read_be(&header[8..12]));
+                    for i in (0..cell_count).rev() {
+                        let cell = header.get(12 + i * 2..14 + i * 2);
+                        let cell = read_be(cell.ok_or(Error::DatabaseError)?) as usize;
+                        let child = page.get(cell..cell + 4).ok_or(Error::DatabaseError)?;
+                        pages.push(read_be(child));
+                    }
+                }
+                // Leaf table page: [payload size][rowid][payload]
+                0x0D => {
+                    for i in 0..cell_count {
+                        let cell = header.get(8 + i * 2..10 + i * 2);
+                        let cell = read_be(cell.ok_or(Error::DatabaseError)?) as usize;
+                        let cell = page.get(cell..).ok_or(Error::DatabaseError)?;
+                        let (payload_len, n) = read_varint(cell)?;
+                        let (_, m) = read_varint(&cell[n..])?;
+                        let payload = self.read_payload(&cell[n + m..], payload_len as usize)?;
+                        rows.push(parse_record(&payload)?);
+                    }
+                }
+                _ => return Err(Error::DatabaseError),
+            }
+        }
+
+        Ok(rows)
+    }
+
+    fn read_payload(&self, cell: &[u8], len: usize) -> DumpResult<Vec<u8>> {
+        let usable = self.usable_size;
+        let max_local = usable - 35;
+        if len <= max_local {
+            return cell
+                .get(..len)
+                .map(|p| p.to_vec())
+                .ok_or(Error::DatabaseError);
+        }
+
+        let min_local = (usable - 12) * 32 / 255 - 23;
+        let local = min_local + (len - min_local) % (usable - 4);
+        let local = if local <= max_local { local } else { min_local };
+
+        let mut payload = cell.get(..local).ok_or(Error::DatabaseError)?.to_vec();
+        let mut next = read_be(cell.get(local..local + 4).ok_or(Error::DatabaseError)?);
+        let mut visited = HashSet::new();
+        while payload.len() < len {
+            if !visited.insert(next) {
+                return Err(Error::DatabaseError);
+            }
+            let page = self.page(next)?;
+            next = read_be(&page[..4]);
+            let chunk = (len - payload.len()).min(usable - 4);
+            payload.extend(&page[4..4 + chunk]);
+        }
+
+        Ok(payload)
+    }
+}
+
+fn parse_record(payload: &[u8]) -> DumpResult<Vec<Value>> {
+    let (header_len, mut pos) = read_varint(payload)?;
+    let mut types = vec![];
+    while pos < header_len as usize {
+        let (serial_type, n) = read_varint(payload.get(pos..).ok_or(Error::DatabaseError)?)?;
+        types.push(serial_type);
+        pos += n;
+    }
+
+    let mut body = payload
+        .get(header_len as usize..)
+        .ok_or(Error::DatabaseError)?;
+    let mut take = |len: usize| -> DumpResult<&[u8]> {
+        let (value, rest) = (body.get(..len), body.get(len..));
+        body = rest.ok_or(Error::DatabaseError)?;
+        value.ok_or(Error::DatabaseError)
+    };
+
+    types
+        .into_iter()
+        .map(|serial_type| {
+            Ok(match serial_type {
+                0 => Value::Null,
+                1..=6 => {
+                    let len = [0, 1, 2, 3, 4, 6, 8][serial_type as usize];
+                    let raw = take(len)?;
+                    let shift = 64 - len * 8;
+                    Value::Integer(((read_be(raw) << shift) as i64) >> shift)
+                }
+                7 => Value::Real(f64::from_bits(read_be(take(8)?))),
+                8 => Value::Integer(0),
+                9 => Value::Integer(1),
+                n if n >= 12 && n.is_multiple_of(2) => {
+                    Value::Blob(take((n as usize - 12) / 2)?.to_vec())
+                }
+                n if n >= 13 => {
+                    Value::Text(String::from_utf8_lossy(take((n as usize - 13) / 2)?).into_owned())
+                }
+                _ => return Err(Error::DatabaseError),
+            })
+        })
+        .collect()
+}
+
+/// Column names of a `CREATE TABLE` statement, in declaration order.
+fn parse_columns(sql: &str) -> Vec<String> {
+    let (Some(start), Some(end)) = (sql.find('('), sql.rfind(')')) else {
+        return vec![];
+    };
+
+    let mut columns = vec![];
+    let mut depth = 0;
+    let mut item = String::new();
+    for c in sql[start + 1..end].chars().chain([',']) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                let name = item
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .trim_matches(['"', '`', '[', ']', '\'']);
+                let constraint = ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+                    .iter()
+                    .any(|k| name.eq_ignore_ascii_case(k));
+                if !name.is_empty() && !constraint {
+                    columns.push(name.to_owned());
+                }
+                item.clear();
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+
+    columns
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_columns, Database, Value, HEADER};
+
+    fn empty_database(pages: usize) -> Vec<u8> {
+        let mut data = vec![0u8; 512 * pages];
+        data[..16].copy_from_slice(HEADER);
+        data[16..18].copy_from_slice(&512u16.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        assert_eq!(
+            parse_columns(
+                "CREATE TABLE t (\"Id\" INTEGER PRIMARY KEY, `Name` TEXT, Size NUMERIC(10, 2), PRIMARY KEY (Id))"
+            ),
+            ["Id", "Name", "Size"]
+        );
+    }
+
+    #[test]
+    fn test_malformed_btree() {
+        // Interior root page whose right-most child is itself
+        let mut data = empty_database(1);
+        data[100] = 0x05;
+        data[108..112].copy_from_slice(&1u32.to_be_bytes());
+        let db = Database::new(&data).unwrap();
+        assert!(db.read_table("t").is_err());
+
+        // Leaf root page claiming more cells than fit in it
+        let mut data = empty_database(1);
+        data[100] = 0x0D;
+        data[103..105].copy_from_slice(&0xFFFFu16.to_be_bytes());
+        let db = Database::new(&data).unwrap();
+        assert!(db.read_table("t").is_err());
+    }
+
+    #[test]
+    fn test_overflow_cycle() {
+        // Overflow page 2 links back to itself
+        let mut data = empty_database(2);
+        data[512..516].copy_from_slice(&2u32.to_be_bytes());
+        let db = Database::new(&data).unwrap();
+
+        let len = 2000;
+        let mut cell = vec![0u8; 600];
+        let local = (512 - 12) * 32 / 255 - 23;
+        cell[local..local + 4].copy_from_slice(&2u32.to_be_bytes());
+        assert!(db.read_payload(&cell, len).is_err());
+    }
+
+    #[test]
+    fn test_read_table() {
+        let data = std::fs::read("./tests/kgg_infra.db").unwrap();
+        let db = Database::new(&data).unwrap();
+        let (columns, rows) = db.read_table("ShareFileItems").unwrap();
+
+        assert_eq!(
+            columns,
+            ["Id", "FileName", "EncryptionKeyId", "EncryptionKey", "Size"]
+        );
+        assert_eq!(rows.len(), 40);
+
+        let row = rows
+            .iter()
+            .find(|r| r[1] == Value::Text("song05.kgg".into()))
+            .unwrap();
+        assert_eq!(row[4], Value::Integer(5000));
+        let key = row[3].as_str().unwrap();
+        assert_eq!(key.len(), 2000);
+        assert!(key.starts_with("VLmZ/bK4OPh1"));
+        assert!(key.ends_with("deZ6e/Gyyrwz"));
+    }
+}
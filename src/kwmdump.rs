@@ -0,0 +1,200 @@
+use crate::decryptor::Decryptor;
+use crate::error::{DumpResult, Error};
+use crate::MediaFormat;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub(crate) const MAGIC: &[u8; 16] = b"yeelion-kuwo-tme";
+pub(crate) const MAGIC_LEGACY: &[u8; 16] = b"yeelion-kuwo\0\0\0\0";
+
+const KEY: &[u8; 32] = b"MoOtOiTvINGwd2E6n0E1i7L5t2IoOoNk";
+
+const HEADER_LEN: u64 = 0x400;
+
+pub struct KwmDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    mask: [u8; 32],
+    resource_id: u64,
+    bitrate: u64,
+    format: String,
+}
+
+/// Build the XOR mask from the decimal resource id, repeated or truncated to
+/// the length of the fixed key.
+fn build_mask(resource_id: u64) -> [u8; 32] {
+    let id = resource_id.to_string();
+    let mut mask = [0u8; 32];
+    for (i, (m, id)) in mask.iter_mut().zip(id.bytes().cycle()).enumerate() {
+        *m = KEY[i] ^ id;
+    }
+    mask
+}
+
+/// Split a bitrate/format string such as `20900kflac` into `(20900, "flac")`.
+fn parse_format(raw: &[u8]) -> (u64, String) {
+    let raw = String::from_utf8_lossy(raw);
+    let raw = raw.trim_end_matches('\0').trim();
+    let sep = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let bitrate = raw[..sep].parse().unwrap_or(0);
+    let format = raw[sep..].trim_start_matches(['k', 'K']).trim();
+    (bitrate, format.to_ascii_lowercase())
+}
+
+impl<R: Read + Seek> KwmDump<R> {
+    pub fn from_reader(mut reader: R) -> DumpResult<Self> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| Error::FormatError)?;
+
+        if &header[..16] != MAGIC && &header[..16] != MAGIC_LEGACY {
+            return Err(Error::FormatError);
+        }
+
+        let mut id_buf = [0u8; 8];
+        id_buf.copy_from_slice(&header[0x18..0x20]);
+        let resource_id = u64::from_le_bytes(id_buf);
+        let (bitrate, format) = parse_format(&header[0x30..0x40]);
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            mask: build_mask(resource_id),
+            resource_id,
+            bitrate,
+            format,
+        })
+    }
+
+    pub fn get_resource_id(&self) -> u64 {
+        self.resource_id
+    }
+
+    /// Bitrate in kbps, as stated in the header.
+    pub fn get_bitrate(&self) -> u64 {
+        self.bitrate
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        self.format.as_str().into()
+    }
+
+    pub fn get_format_str(&self) -> &str {
+        &self.format
+    }
+
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(HEADER_LEN))?;
+        self.cursor = 0;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for KwmDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        for (index, byte) in buf.iter_mut().enumerate().take(size) {
+            *byte ^= self.mask[(self.cursor + index) & 0x1F];
+        }
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for KwmDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => SeekFrom::Start(HEADER_LEN + p),
+            p => p,
+        };
+        let new_pos = self.reader.seek(pos)?;
+
+        if new_pos < HEADER_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked to KWM illegal area",
+            ));
+        }
+
+        self.cursor = (new_pos - HEADER_LEN) as usize;
+        Ok(self.cursor as u64)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for KwmDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.get_format())
+    }
+
+    fn move_to_start(&mut self) -> std::io::Result<()> {
+        KwmDump::move_to_start(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_mask, parse_format, KwmDump, HEADER_LEN, MAGIC};
+    use crate::MediaFormat;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!(
+            parse_format(b"20900kflac\0\0\0\0\0\0"),
+            (20900, "flac".into())
+        );
+        assert_eq!(parse_format(b"320kmp3\0"), (320, "mp3".into()));
+        assert_eq!(parse_format(b"\0\0\0\0"), (0, "".into()));
+    }
+
+    #[test]
+    fn test_build_mask() {
+        let mask = build_mask(12345);
+        let expected: Vec<u8> = b"MoOtOiTvINGwd2E6n0E1i7L5t2IoOoNk"
+            .iter()
+            .zip(b"12345123451234512345123451234512")
+            .map(|(k, id)| k ^ id)
+            .collect();
+        assert_eq!(mask[..], expected[..]);
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let audio: Vec<u8> = b"fLaC"
+            .iter()
+            .copied()
+            .chain((0..1000u32).map(|n| n as u8))
+            .collect();
+        let mask = build_mask(156483171);
+
+        let mut file = MAGIC.to_vec();
+        file.resize(0x18, 0);
+        file.extend(156483171u64.to_le_bytes());
+        file.resize(0x30, 0);
+        file.extend(b"20900kflac");
+        file.resize(HEADER_LEN as usize, 0);
+        file.extend(audio.iter().enumerate().map(|(i, b)| b ^ mask[i % 32]));
+
+        let mut dump = KwmDump::from_reader(Cursor::new(file)).unwrap();
+        assert_eq!(dump.get_resource_id(), 156483171);
+        assert_eq!(dump.get_bitrate(), 20900);
+        assert!(matches!(dump.get_format(), MediaFormat::fLaC));
+
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio);
+
+        dump.seek(SeekFrom::Start(100)).unwrap();
+        let mut res = [0u8; 8];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(res[..], audio[100..108]);
+
+        assert!(KwmDump::from_reader(Cursor::new(vec![0u8; 0x400])).is_err());
+    }
+}
@@ -1,5 +1,37 @@
+//! Without the default `std` feature, only the decryption core is built: the
+//! slice-based [`ncmdump::NcmHeader`], the keystreams and the feeders.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod decryptor;
+pub mod feed;
+#[cfg(feature = "std")]
+pub mod jooxdump;
+#[cfg(feature = "std")]
+pub mod kgmdump;
+#[cfg(feature = "std")]
+pub mod kwmdump;
+#[cfg(feature = "symphonia")]
+pub mod media_source;
+#[cfg(feature = "std")]
+pub mod mmkv;
 pub mod ncmdump;
+#[cfg(feature = "parallel")]
+pub mod parallel;
 pub mod qmcdump;
+#[cfg(feature = "std")]
+pub mod registry;
+#[cfg(feature = "std")]
+pub mod xmdump;
+#[cfg(feature = "std")]
+pub mod xmlydump;
+#[cfg(feature = "std")]
+pub use decryptor::{open_any, Decryptor};
 pub use ncmdump::error;
 pub use ncmdump::MediaFormat;
 pub use ncmdump::NcmInfo;
+#[cfg(feature = "std")]
+pub use registry::{Format, FormatRegistry};
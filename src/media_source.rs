@@ -0,0 +1,145 @@
+//! Decrypted audio as a [`MediaSource`] of the symphonia decoder, to play a
+//! file without writing it out first.
+
+use crate::decryptor::Decryptor;
+use crate::error::DumpResult;
+use crate::MediaFormat;
+use std::io::{Read, Seek, SeekFrom};
+use symphonia::core::io::MediaSource;
+use symphonia::core::probe::Hint;
+
+pub struct DumpSource<D> {
+    dump: D,
+    format: MediaFormat,
+    byte_len: u64,
+}
+
+impl<D: Decryptor + Send + Sync> DumpSource<D> {
+    /// Wrap `dump`, positioned at the start of the audio.
+    pub fn new(mut dump: D) -> DumpResult<Self> {
+        let format = dump.format()?;
+        let byte_len = dump.seek(SeekFrom::End(0))?;
+        dump.move_to_start()?;
+        Ok(Self {
+            dump,
+            format,
+            byte_len,
+        })
+    }
+
+    pub fn format(&self) -> MediaFormat {
+        self.format
+    }
+
+    /// What to tell the probe about the container.
+    pub fn hint(&self) -> Hint {
+        format_hint(self.format)
+    }
+
+    pub fn into_inner(self) -> D {
+        self.dump
+    }
+}
+
+/// A probe hint naming the extension of `format`, if it is a known container.
+pub fn format_hint(format: MediaFormat) -> Hint {
+    let mut hint = Hint::new();
+    let extension = match format {
+        MediaFormat::fLaC => "flac",
+        MediaFormat::ID3v2 => "mp3",
+        MediaFormat::M4A => "m4a",
+        MediaFormat::WAV => "wav",
+        MediaFormat::Ogg => "ogg",
+        MediaFormat::Opus => "opus",
+        MediaFormat::AAC => "aac",
+        MediaFormat::APE => "ape",
+        MediaFormat::WavPack => "wv",
+        MediaFormat::DSF => "dsf",
+        MediaFormat::Unsupported | MediaFormat::Unknown => return hint,
+    };
+    hint.with_extension(extension);
+    hint
+}
+
+impl<D: Read> Read for DumpSource<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.dump.read(buf)
+    }
+}
+
+impl<D: Seek> Seek for DumpSource<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.dump.seek(pos)
+    }
+}
+
+impl<D: Read + Seek + Send + Sync> MediaSource for DumpSource<D> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.byte_len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DumpSource;
+    use crate::ncmdump::NcmDump;
+    use crate::qmcdump::QmcDump;
+    use crate::MediaFormat;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+    use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_FLAC};
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::{MediaSource, MediaSourceStream};
+    use symphonia::core::meta::MetadataOptions;
+
+    #[test]
+    fn test_decode_ncm() {
+        let dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let source = DumpSource::new(dump).unwrap();
+        assert_eq!(source.format(), MediaFormat::fLaC);
+        assert_eq!(source.byte_len(), Some(61440));
+        assert!(source.is_seekable());
+
+        let hint = source.hint();
+        let stream = MediaSourceStream::new(Box::new(source), Default::default());
+        let probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap();
+        let mut reader = probed.format;
+        let track = reader.default_track().unwrap();
+        assert_eq!(track.codec_params.codec, CODEC_TYPE_FLAC);
+        let track_id = track.id;
+
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .unwrap();
+        for _ in 0..2 {
+            let packet = reader.next_packet().unwrap();
+            assert_eq!(packet.track_id(), track_id);
+            let audio = decoder.decode(&packet).unwrap();
+            assert!(audio.frames() > 0);
+        }
+    }
+
+    #[test]
+    fn test_qmc_source() {
+        let file: Vec<u8> = (0..0x1000u32).map(|n| (n * 7) as u8).collect();
+        let dump = QmcDump::from_reader_with_format(Cursor::new(file), MediaFormat::Ogg);
+        let mut source = DumpSource::new(dump).unwrap();
+        assert_eq!(source.byte_len(), Some(0x1000));
+        assert_eq!(source.format(), MediaFormat::Ogg);
+
+        let mut res = vec![];
+        source.read_to_end(&mut res).unwrap();
+        assert_eq!(res.len(), 0x1000);
+    }
+}
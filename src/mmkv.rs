@@ -0,0 +1,310 @@
+//! Reader for the MMKV key store in which QQ Music clients keep the ekeys of
+//! downloaded `.mflac`/`.mgg` files.
+
+use crate::error::{DumpResult, Error};
+use aes::Aes128;
+use cipher::{BlockEncrypt, KeyInit};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const IV_OFFSET: usize = 12;
+const BLOCK_LEN: usize = 16;
+
+/// Derive the AES key of an encrypted MMKV file from the device value it was
+/// opened with. MMKV uses the first 16 bytes of the value, zero-padded.
+pub fn derive_key(device_value: &str) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    let value = device_value.as_bytes();
+    let len = value.len().min(key.len());
+    key[..len].copy_from_slice(&value[..len]);
+    key
+}
+
+/// Mapping from the path of an audio file to its ekey.
+#[derive(Debug, Default)]
+pub struct MmkvKeyStore {
+    entries: HashMap<String, String>,
+    names: HashMap<String, String>,
+}
+
+impl MmkvKeyStore {
+    /// Parse an unencrypted MMKV file.
+    pub fn from_bytes(data: &[u8]) -> DumpResult<Self> {
+        let (size, payload) = split_payload(data)?;
+        parse_payload(&payload[..size])
+    }
+
+    /// Parse an MMKV file encrypted with `key`. `iv` is stored in the
+    /// companion `.crc` file; older files without one use the key instead.
+    pub fn from_encrypted_bytes(
+        data: &[u8],
+        key: &[u8; 16],
+        iv: Option<&[u8]>,
+    ) -> DumpResult<Self> {
+        let (size, payload) = split_payload(data)?;
+        let mut iv_buf = *key;
+        if let Some(iv) = iv {
+            if iv.len() != BLOCK_LEN {
+                return Err(Error::MmkvError);
+            }
+            iv_buf.copy_from_slice(iv);
+        }
+
+        let mut payload = payload[..size].to_vec();
+        decrypt_cfb(&mut payload, key, &iv_buf);
+        parse_payload(&payload)
+    }
+
+    /// Open an MMKV file on disk. When `device_value` is given, the file is
+    /// decrypted with the IV taken from the `.crc` file next to it.
+    pub fn open(path: &Path, device_value: Option<&str>) -> DumpResult<Self> {
+        let data = std::fs::read(path)?;
+        match device_value {
+            None => Self::from_bytes(&data),
+            Some(value) => {
+                let mut crc_path = path.as_os_str().to_owned();
+                crc_path.push(".crc");
+                let iv = std::fs::read(PathBuf::from(crc_path)).ok().and_then(|crc| {
+                    crc.get(IV_OFFSET..IV_OFFSET + BLOCK_LEN)
+                        .map(|iv| iv.to_vec())
+                });
+                Self::from_encrypted_bytes(&data, &derive_key(value), iv.as_deref())
+            }
+        }
+    }
+
+    /// Find the ekey of `path`, by its full path first and then by file name.
+    pub fn get(&self, path: &Path) -> Option<&str> {
+        path.to_str()
+            .and_then(|p| self.entries.get(p))
+            .or_else(|| {
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| self.names.get(n))
+            })
+            .map(|s| s.as_str())
+    }
+
+    pub fn entries(&self) -> &HashMap<String, String> {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        if let Some(name) = file_name(&key) {
+            self.names.insert(name.to_owned(), value.clone());
+        }
+        self.entries.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(name) = file_name(key) {
+            self.names.remove(name);
+        }
+        self.entries.remove(key);
+    }
+}
+
+fn file_name(path: &str) -> Option<&str> {
+    path.rsplit(['/', '\\']).next().filter(|n| !n.is_empty())
+}
+
+/// An MMKV file starts with the length of the valid payload.
+fn split_payload(data: &[u8]) -> DumpResult<(usize, &[u8])> {
+    let size_buf: [u8; 4] = data
+        .get(..4)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(Error::MmkvError)?;
+    let size = u32::from_le_bytes(size_buf) as usize;
+    let payload = &data[4..];
+    if size > payload.len() {
+        return Err(Error::MmkvError);
+    }
+    Ok((size, payload))
+}
+
+fn decrypt_cfb(buf: &mut [u8], key: &[u8; 16], iv: &[u8; 16]) {
+    let aes = Aes128::new(key.into());
+    let mut feedback = *iv;
+    for chunk in buf.chunks_mut(BLOCK_LEN) {
+        let mut stream = feedback.into();
+        aes.encrypt_block(&mut stream);
+        feedback[..chunk.len()].copy_from_slice(chunk);
+        chunk.iter_mut().zip(stream).for_each(|(b, s)| *b ^= s);
+    }
+}
+
+struct PbReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PbReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn read_varint(&mut self) -> DumpResult<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.buf.get(self.pos).ok_or(Error::MmkvError)?;
+            self.pos += 1;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::MmkvError)
+    }
+
+    fn read_bytes(&mut self) -> DumpResult<&'a [u8]> {
+        let len = self.read_varint()? as usize;
+        let end = self.pos.checked_add(len).ok_or(Error::MmkvError)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(Error::MmkvError)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+/// The payload is a size placeholder followed by `[key][value]` pairs, both
+/// length-prefixed. String values carry another length prefix inside. Later
+/// pairs override earlier ones and an empty value marks a removed key.
+fn parse_payload(payload: &[u8]) -> DumpResult<MmkvKeyStore> {
+    let mut reader = PbReader {
+        buf: payload,
+        pos: 0,
+    };
+    let mut store = MmkvKeyStore::default();
+
+    reader.read_varint()?;
+    while !reader.is_empty() {
+        let key = reader.read_bytes()?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| Error::MmkvError)?;
+        let value = reader.read_bytes()?;
+        if value.is_empty() {
+            store.remove(&key);
+            continue;
+        }
+
+        let value = PbReader { buf: value, pos: 0 }.read_bytes()?;
+        let value = String::from_utf8(value.to_vec()).map_err(|_| Error::MmkvError)?;
+        store.insert(key, value);
+    }
+
+    Ok(store)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{derive_key, MmkvKeyStore, BLOCK_LEN};
+    use aes::Aes128;
+    use cipher::{BlockEncrypt, KeyInit};
+    use std::path::Path;
+
+    fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+        write_varint(buf, bytes.len() as u64);
+        buf.extend(bytes);
+    }
+
+    fn build_payload(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut payload = vec![];
+        write_varint(&mut payload, 0x00FF_FFFF);
+        for (key, value) in pairs {
+            write_bytes(&mut payload, key.as_bytes());
+            let mut inner = vec![];
+            if !value.is_empty() {
+                write_bytes(&mut inner, value.as_bytes());
+            }
+            write_bytes(&mut payload, &inner);
+        }
+        payload
+    }
+
+    fn build_file(payload: &[u8]) -> Vec<u8> {
+        let mut file = (payload.len() as u32).to_le_bytes().to_vec();
+        file.extend(payload);
+        file.extend([0u8; 32]);
+        file
+    }
+
+    const PAIRS: [(&str, &str); 4] = [
+        ("/storage/emulated/0/qqmusic/song/a.mflac", "ZWtleS1h"),
+        ("/storage/emulated/0/qqmusic/song/b.mgg", "ZWtleS1i"),
+        ("/storage/emulated/0/qqmusic/song/c.mflac", "ZWtleS1j"),
+        ("/storage/emulated/0/qqmusic/song/c.mflac", ""),
+    ];
+
+    fn check(store: &MmkvKeyStore) {
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            store.get(Path::new("/storage/emulated/0/qqmusic/song/a.mflac")),
+            Some("ZWtleS1h")
+        );
+        assert_eq!(store.get(Path::new("./download/b.mgg")), Some("ZWtleS1i"));
+        assert_eq!(store.get(Path::new("c.mflac")), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let file = build_file(&build_payload(&PAIRS));
+        check(&MmkvKeyStore::from_bytes(&file).unwrap());
+
+        assert!(MmkvKeyStore::from_bytes(&[0xFF, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_encrypted() {
+        let key = derive_key("device-value-for-test");
+        assert_eq!(&key, b"device-value-for");
+        let iv = *b"0123456789abcdef";
+
+        let mut payload = build_payload(&PAIRS);
+        let aes = Aes128::new((&key).into());
+        let mut feedback = iv;
+        for chunk in payload.chunks_mut(BLOCK_LEN) {
+            let mut stream = feedback.into();
+            aes.encrypt_block(&mut stream);
+            chunk.iter_mut().zip(stream).for_each(|(b, s)| *b ^= s);
+            feedback[..chunk.len()].copy_from_slice(chunk);
+        }
+
+        let file = build_file(&payload);
+        check(&MmkvKeyStore::from_encrypted_bytes(&file, &key, Some(&iv)).unwrap());
+    }
+
+    #[test]
+    fn test_parse_encrypted_known_answer() {
+        // One pair, encrypted with AES-128-CFB by OpenSSL
+        let payload = [
+            0xF5, 0xEF, 0x33, 0xE1, 0xEC, 0xA2, 0xA3, 0x88, 0x70, 0x61, 0xDF, 0x87, 0xC3, 0x99,
+            0x7F, 0xC3, 0x82, 0x8E, 0x30, 0xAB, 0x3C, 0xA0, 0x4B, 0xCA, 0xB1, 0xA6, 0xF9, 0x26,
+            0x83, 0x5F, 0x7C, 0x6B, 0x14, 0xAE, 0xDA, 0x7F, 0x23, 0xA5, 0x03, 0xAB, 0x6C, 0xF2,
+            0x45, 0x27, 0x4F, 0x93, 0xD9, 0x33, 0xEF, 0x2E, 0x1F, 0xCC,
+        ];
+        let key = derive_key("device-value-for-test");
+        let file = build_file(&payload);
+        let store =
+            MmkvKeyStore::from_encrypted_bytes(&file, &key, Some(b"0123456789abcdef")).unwrap();
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            store.get(Path::new("/sdcard/qqmusic/song/known.mflac")),
+            Some("a25vd24tYW5zd2Vy")
+        );
+    }
+}
@@ -1,27 +1,34 @@
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use serde::Deserialize;
-use std::io::{Read, Seek, SeekFrom, Write};
 
+#[cfg(feature = "tokio")]
+pub mod async_dump;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+mod dump;
 pub mod error;
+pub(crate) mod feed;
+pub mod header;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "tokio")]
+pub use async_dump::AsyncNcmDump;
+#[cfg(feature = "std")]
+pub use cache::NcmCacheDump;
+#[cfg(feature = "std")]
+pub use dump::NcmDump;
 use error::{DumpResult, Error};
+pub use header::NcmHeader;
+#[cfg(feature = "std")]
+pub use stream::NcmStreamDump;
 
 #[cfg(feature = "tag")]
-mod tag;
-#[cfg(feature = "tag")]
-use tag::TagWrite;
-
-#[cfg(feature = "tag")]
-use audiotags::{AudioTagEdit, FlacTag, Id3v2Tag, Picture};
-
-#[cfg(feature = "tag")]
-use id3::Tag as ID3v2InnerTag;
-
-#[cfg(feature = "tag")]
-use metaflac::Tag as FlacInnerTag;
-
-#[cfg(feature = "tag")]
-use image;
+pub(crate) mod tag;
 
 #[deprecated]
 #[allow(unused_macros)]
@@ -34,31 +41,7 @@ macro_rules! build_tag {
     }};
 }
 
-macro_rules! write_tag {
-    ($tag:ty, $inner_tag:ty, $reader:ident, $writer:ident, $info:ident, $cover:ident) => {{
-        let inner_tag = <$inner_tag>::read_from($reader)?;
-        let mut tag: $tag = inner_tag.into();
-        tag.set_title(&($info).name);
-        tag.set_artist(&construct_artist_list(&($info).artist));
-        tag.set_album_title(&($info).album);
-        tag.set_album_cover($cover);
-        let mut inner_tag: $inner_tag = tag.into();
-        inner_tag.write_with_tag_to($writer)?;
-
-        Ok(())
-    }};
-}
-
-pub struct NcmDump<R: Read> {
-    reader: R,
-    cursor: usize,
-    key_box: Vec<u8>,
-    info_range: (u64, u64),
-    image_range: (u64, u64),
-    data_start: u64,
-}
-
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
 pub struct NcmInfo {
     #[serde(rename = "musicName")]
     pub name: String,
@@ -78,11 +61,22 @@ pub struct NcmInfo {
     pub alias: Option<Vec<String>>,
 }
 
+/// Bytes of decrypted audio needed by [`MediaFormat::sniff`].
+pub const SNIFF_LEN: usize = 64;
+
 #[allow(non_camel_case_types)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MediaFormat {
     fLaC,
     ID3v2,
+    M4A,
+    WAV,
+    Ogg,
+    Opus,
+    AAC,
+    APE,
+    WavPack,
+    DSF,
 
     Unsupported,
     Unknown,
@@ -93,155 +87,65 @@ impl From<&str> for MediaFormat {
         match value {
             "flac" => Self::fLaC,
             "mp3" => Self::ID3v2,
+            "m4a" => Self::M4A,
+            "wav" => Self::WAV,
+            "ogg" => Self::Ogg,
+            "opus" => Self::Opus,
+            "aac" => Self::AAC,
+            "ape" => Self::APE,
+            "wv" => Self::WavPack,
+            "dsf" => Self::DSF,
 
             _ => Self::Unsupported,
         }
     }
 }
 
-impl<R: Read + Seek> NcmDump<R> {
-    pub fn from_reader(mut reader: R) -> DumpResult<Self> {
-        let mut format_buf = [0u8; 10];
-        let size = reader.read(&mut format_buf)?;
-        if size != 10 || !check_format(&format_buf) {
-            return Err(Error::FormatError);
+impl MediaFormat {
+    /// Guess the container from the first bytes of the decrypted audio.
+    /// [`SNIFF_LEN`] bytes are enough for every known container.
+    pub fn sniff(header: &[u8]) -> Self {
+        match header {
+            // The first page of an Ogg stream holds the codec header alone
+            [b'O', b'g', b'g', b'S', ..] if header.get(28..36) == Some(b"OpusHead") => Self::Opus,
+            [b'f', b'L', b'a', b'C', ..] => Self::fLaC,
+            [b'I', b'D', b'3', ..] => Self::ID3v2,
+            // MPEG audio frame sync. Layer bits of 0 would be ADTS AAC.
+            [0xFF, b, ..] if b & 0xE0 == 0xE0 && b & 0x06 != 0 => Self::ID3v2,
+            [0xFF, b, ..] if b & 0xF6 == 0xF0 => Self::AAC,
+            [b'O', b'g', b'g', b'S', ..] => Self::Ogg,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Self::WAV,
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => Self::M4A,
+            [b'M', b'A', b'C', b' ', ..] => Self::APE,
+            [b'w', b'v', b'p', b'k', ..] => Self::WavPack,
+            [b'D', b'S', b'D', b' ', ..] => Self::DSF,
+            _ => Self::Unknown,
         }
-
-        let mut key_length_buf = [0u8; 4];
-        let size = reader.read(&mut key_length_buf)?;
-        if size != 4 {
-            return Err(Error::KeyLengthError);
-        }
-        let key_length = u32::from_ne_bytes(key_length_buf) as usize;
-
-        let mut key_buf: Vec<u8> = Vec::new();
-        let size = reader
-            .by_ref()
-            .take(key_length as u64)
-            .read_to_end(&mut key_buf)?;
-        if size != key_length {
-            return Err(Error::KeyLoadError);
-        }
-        let key_box: Vec<u8> = build_key_box_from_encrypted(&key_buf)?;
-
-        let mut info_length_buf = [0u8; 4];
-        let size = reader.read(&mut info_length_buf)?;
-        if size != 4 {
-            return Err(Error::InfoLengthError);
-        }
-        let info_length = u32::from_ne_bytes(info_length_buf);
-        let info_start = reader.stream_position()?;
-        let _info_end = info_start + info_length as u64;
-        reader.seek(SeekFrom::Current(info_length as i64))?;
-
-        // Skip the 9-byte gap
-        reader.seek(SeekFrom::Current(9i64))?;
-
-        let mut image_length_buf = [0u8; 4];
-        let size = reader.read(&mut image_length_buf)?;
-        if size != 4 {
-            return Err(Error::ImageLengthError);
-        }
-        let image_length = u32::from_ne_bytes(image_length_buf);
-        let image_start = reader.stream_position()?;
-        let data_start = reader.seek(SeekFrom::Current(image_length as i64))?;
-
-        Ok(Self {
-            reader,
-            cursor: 0,
-            key_box,
-            info_range: (info_start, info_length as u64),
-            image_range: (image_start, image_length as u64),
-            data_start,
-        })
-    }
-
-    pub fn get_info(&mut self) -> DumpResult<NcmInfo> {
-        let original_pos = self.reader.stream_position()?;
-
-        self.reader.seek(SeekFrom::Start(self.info_range.0))?;
-        let mut info_reader = self.reader.by_ref().take(self.info_range.1);
-        let mut info_buf = vec![];
-        let _ = info_reader.read_to_end(&mut info_buf)?;
-        self.reader.seek(SeekFrom::Start(original_pos))?;
-
-        let info_buf = &mut info_buf[22..];
-        info_buf.iter_mut().for_each(|b| *b ^= 0x63);
-
-        let info_buf = STANDARD
-            .decode(info_buf)
-            .map_err(|_| Error::InfoDecodeError)?;
-
-        let info_buf = decrypt_meta(&info_buf, &INFO_KEY)?;
-        let info_str =
-            String::from_utf8(info_buf[6..].to_owned()).map_err(|_| Error::InfoDecodeError)?;
-
-        serde_json::from_str(&info_str).map_err(|_| Error::InfoDecodeError)
-    }
-
-    pub fn get_image(&mut self) -> DumpResult<Vec<u8>> {
-        let original_pos = self.reader.stream_position()?;
-
-        self.reader.seek(SeekFrom::Start(self.image_range.0))?;
-        let mut image_reader = self.reader.by_ref().take(self.image_range.1);
-        let mut image_buf = vec![];
-        let _ = image_reader.read_to_end(&mut image_buf)?;
-        self.reader.seek(SeekFrom::Start(original_pos))?;
-
-        Ok(image_buf)
     }
 
-    pub fn move_to_start(&mut self) -> std::io::Result<()> {
-        self.reader.seek(SeekFrom::Start(self.data_start))?;
-        self.cursor = 0;
-        Ok(())
+    /// Whether this names a real container.
+    pub fn is_known(&self) -> bool {
+        !matches!(self, Self::Unsupported | Self::Unknown)
     }
+}
 
-    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
-        self.move_to_start()?;
-        std::io::copy(self, writer)?;
-        Ok(())
-    }
+/// Decrypt the info block as stored in the file.
+fn decode_info(mut info_buf: Vec<u8>) -> DumpResult<NcmInfo> {
+    let info_buf = info_buf.get_mut(22..).ok_or(Error::InfoDecodeError)?;
+    info_buf.iter_mut().for_each(|b| *b ^= 0x63);
 
-    #[cfg(feature = "tag")]
-    pub fn write_with_tag(&mut self, writer: &mut (impl Write + Seek)) -> DumpResult<()> {
-        let info = self.get_info()?;
-        let image = self.get_image()?;
-        let image_format = image::guess_format(&image).map_err(|_| Error::ImageFormatError)?;
-        let image_format = image_to_audiotag_mimetype(image_format)?;
-        let cover = Picture::new(&image, image_format);
-        self.move_to_start()?;
-
-        let media_format: MediaFormat = info.format.as_str().into();
-        match media_format {
-            MediaFormat::ID3v2 => {
-                let res: DumpResult<()> = unsafe {
-                    let p = self as *mut Self;
-                    let reader: &mut Self = &mut *p;
-                    write_tag!(Id3v2Tag, ID3v2InnerTag, reader, writer, info, cover)
-                };
-                std::io::copy(self, writer)?;
-                res
-            }
-            MediaFormat::fLaC => {
-                let res: DumpResult<()> =
-                    write_tag!(FlacTag, FlacInnerTag, self, writer, info, cover);
-                std::io::copy(self, writer)?;
-                res
-            }
-            _ => Err(Error::TagBuildError("Unsupported format".to_string())),
-        }?;
+    let info_buf = STANDARD
+        .decode(info_buf)
+        .map_err(|_| Error::InfoDecodeError)?;
 
-        Ok(())
-    }
-}
+    let info_buf = decrypt_meta(&info_buf, &INFO_KEY)?;
+    let info_str =
+        String::from_utf8(info_buf[6..].to_owned()).map_err(|_| Error::InfoDecodeError)?;
 
-fn construct_artist_list(artists: &[(String, u64)]) -> String {
-    let artists: Vec<&str> = artists.iter().map(|(s, _)| s.as_str()).collect();
-    artists.join(",")
+    serde_json::from_str(&info_str).map_err(|_| Error::InfoDecodeError)
 }
 
-const FORMAT: [u8; 8] = [b'C', b'T', b'E', b'N', b'F', b'D', b'A', b'M'];
+pub(crate) const FORMAT: [u8; 8] = [b'C', b'T', b'E', b'N', b'F', b'D', b'A', b'M'];
 
 fn check_format(format: &[u8]) -> bool {
     let real_format = format.split_at(8).0;
@@ -267,7 +171,9 @@ fn build_key_box_from_encrypted(encrypted_key: &[u8]) -> DumpResult<Vec<u8>> {
     Ok(build_key_box(key))
 }
 
-fn decrypt_meta(encrypted: &[u8], key: &[u8]) -> DumpResult<Vec<u8>> {
+/// Decrypt AES-128-ECB data with PKCS#7 padding, as the key and the info of
+/// NCM files are.
+pub fn decrypt_meta(encrypted: &[u8], key: &[u8]) -> DumpResult<Vec<u8>> {
     Aes128::new(key.into())
         .decrypt_padded_vec_mut::<Pkcs7>(encrypted)
         .map_err(|_| error::Error::KeyDecryptError)
@@ -275,7 +181,8 @@ fn decrypt_meta(encrypted: &[u8], key: &[u8]) -> DumpResult<Vec<u8>> {
 
 const BOX_LEN: usize = 256;
 
-fn build_key_box(key: &[u8]) -> Vec<u8> {
+/// The RC4-like key box, from the decrypted key of the file.
+pub fn build_key_box(key: &[u8]) -> Vec<u8> {
     let mut key_box = (0..BOX_LEN).map(|n| n as u8).collect::<Vec<u8>>();
     let mut last_byte = 0;
     let l = key.len();
@@ -295,68 +202,47 @@ fn build_key_box(key: &[u8]) -> Vec<u8> {
     key_box
 }
 
-impl<R: Read> Read for NcmDump<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let size = self.reader.read(buf)?;
-        decrypt_data(buf, &self.key_box, self.cursor);
-        self.cursor += size;
-        Ok(size)
-    }
-}
-
-fn decrypt_data(buf: &mut [u8], key_box: &[u8], offset: usize) {
-    for (index, byte) in buf.iter_mut().enumerate() {
-        let box_offset = (index + offset + 1) & 0xFF;
+/// The byte XORed with the audio at every offset of a period, laid out twice
+/// so that a whole period starting anywhere in it is one slice.
+pub fn build_key_stream(key_box: &[u8]) -> Vec<u8> {
+    let period = (0..BOX_LEN).map(|offset| {
+        let box_offset = (offset + 1) & 0xFF;
         let index_1 = key_box[box_offset];
-        let index_2 = key_box[(key_box[box_offset] as usize + box_offset) & 0xFF];
-        let key_index = index_1.overflowing_add(index_2).0;
-        *byte ^= key_box[key_index as usize];
-        // let j = ((offset + index + 1) & 0xff) as usize;
-        // let key_index = (key_box[j].overflowing_add(key_box[(key_box[j] as usize + j) & 0xff]).0) & 0xff;
-        // *byte ^= key_box[key_index as usize] as u8;
-    }
+        let index_2 = key_box[(index_1 as usize + box_offset) & 0xFF];
+        key_box[index_1.wrapping_add(index_2) as usize]
+    });
+    period.clone().chain(period).collect()
 }
 
-impl<R: Read + Seek> Seek for NcmDump<R> {
-    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        let new_pos = self.reader.seek(pos)?;
-
-        if new_pos < self.data_start {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Seeked to NCM illegal area",
-            ));
-        }
-
-        self.cursor = (new_pos - self.data_start) as usize;
-        Ok(self.cursor as u64)
+/// Decrypt `buf` in place, audio that starts at `offset` of the audio, with a
+/// keystream from [`build_key_stream`].
+pub fn decrypt_data(buf: &mut [u8], key_stream: &[u8], offset: usize) {
+    let phase = offset % BOX_LEN;
+    let key = &key_stream[phase..phase + BOX_LEN];
+    for chunk in buf.chunks_mut(BOX_LEN) {
+        xor_words(chunk, &key[..chunk.len()]);
     }
 }
 
-fn image_to_audiotag_mimetype(image_mime: image::ImageFormat) -> DumpResult<audiotags::MimeType> {
-    match image_mime {
-        image::ImageFormat::Png => Ok(audiotags::MimeType::Png),
-        image::ImageFormat::Jpeg => Ok(audiotags::MimeType::Jpeg),
-        image::ImageFormat::Gif => Ok(audiotags::MimeType::Gif),
-        image::ImageFormat::Tiff => Ok(audiotags::MimeType::Tiff),
-        image::ImageFormat::Bmp => Ok(audiotags::MimeType::Bmp),
-        _ => Err(Error::ImageUnsupportedError),
+/// XOR `key` into `buf` a machine word at a time.
+pub(crate) fn xor_words(buf: &mut [u8], key: &[u8]) {
+    let mut words = buf.chunks_exact_mut(8);
+    let mut key_words = key.chunks_exact(8);
+    for (word, key_word) in (&mut words).zip(&mut key_words) {
+        let res = u64::from_ne_bytes((&*word).try_into().unwrap())
+            ^ u64::from_ne_bytes(key_word.try_into().unwrap());
+        word.copy_from_slice(&res.to_ne_bytes());
     }
+    words
+        .into_remainder()
+        .iter_mut()
+        .zip(key_words.remainder())
+        .for_each(|(byte, k)| *byte ^= k);
 }
 
 #[cfg(test)]
 mod test {
-    use std::{fs::File, io::Read};
-
-    use crate::ncmdump::{build_key_box, decrypt_meta};
-
-    use super::{NcmDump, NcmInfo};
-
-    #[test]
-    fn test_build_from_ncm_file() {
-        let reader = File::open("./tests/test.ncm").unwrap();
-        let _ = NcmDump::from_reader(reader).unwrap();
-    }
+    use super::{build_key_box, build_key_stream, decrypt_data, decrypt_meta};
 
     #[test]
     fn test_build_keybox() {
@@ -461,90 +347,23 @@ mod test {
     }
 
     #[test]
-    fn test_decrypt_file() {
-        let reader = File::open("./tests/test.ncm").unwrap();
-        let mut dump = NcmDump::from_reader(reader).unwrap();
-
-        let mut data: Vec<u8> = vec![];
-        let size = dump.read_to_end(&mut data).unwrap();
-
-        assert_eq!(size, 61440);
-        assert_eq!(
-            data[0..16],
-            [
-                0x66, 0x4c, 0x61, 0x43, 0x00, 0x00, 0x00, 0x22, 0x12, 0x00, 0x12, 0x00, 0x00, 0x01,
-                0x01, 0x00,
-            ]
-        );
-        assert_eq!(
-            data[61424..],
-            [
-                0x8b, 0x25, 0x88, 0x08, 0x4b, 0x49, 0x89, 0xc2, 0xba, 0xe3, 0xda, 0x88, 0x48, 0xc1,
-                0x09, 0x7b,
-            ]
-        );
-    }
-
-    #[test]
-    fn test_get_info() {
-        let reader = File::open("./tests/test.ncm").unwrap();
-        let mut dump = NcmDump::from_reader(reader).unwrap();
-
-        let info = dump.get_info().unwrap();
-        assert_eq!(
-            info,
-            NcmInfo {
-                name: "寒鸦少年".to_string(),
-                id: 1305366556,
-                album: "寒鸦少年".to_string(),
-                artist: vec![("华晨宇".into(), 861777)],
-                bitrate: 923378,
-                duration: 315146,
-                format: "flac".to_string(),
-                mv_id: Some(0),
-                alias: Some(vec!["电视剧《斗破苍穹》主题曲".into()]),
-            },
-        )
-    }
-
-    #[test]
-    fn test_get_image() {
-        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
-
-        let image = dump.get_image().unwrap();
-        assert_eq!(image.len(), 39009);
-        assert_eq!(
-            image[..16],
-            [
-                0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10, 0x4a, 0x46, 0x49, 0x46, 0x00, 0x01, 0x01, 0x01,
-                0x00, 0x48,
-            ],
-        );
-        assert_eq!(
-            image[38993..],
-            [
-                0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
-                0xff, 0xd9,
-            ],
-        );
-    }
+    fn test_decrypt_data() {
+        let key_box = build_key_box(b"some key");
+        let key_stream = build_key_stream(&key_box);
+        let data: Vec<u8> = (0..1000u32).map(|n| (n * 31) as u8).collect();
+
+        for (offset, len) in [(0, 1000), (1, 999), (255, 7), (256, 300), (77777, 513)] {
+            let mut expected = data[..len].to_vec();
+            for (index, byte) in expected.iter_mut().enumerate() {
+                let box_offset = (index + offset + 1) & 0xFF;
+                let index_1 = key_box[box_offset];
+                let index_2 = key_box[(index_1 as usize + box_offset) & 0xFF];
+                *byte ^= key_box[index_1.wrapping_add(index_2) as usize];
+            }
 
-    #[test]
-    fn test_write_with_tag() {
-        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
-        let mut writer = File::options()
-            .create(true)
-            .write(true)
-            .open("./tests/test.flac")
-            .unwrap();
-        dump.write_to(&mut writer).unwrap();
-
-        let mut dump = NcmDump::from_reader(File::open("./tests/sample.ncm").unwrap()).unwrap();
-        let mut writer = File::options()
-            .create(true)
-            .write(true)
-            .open("./tests/sample.flac")
-            .unwrap();
-        dump.write_with_tag(&mut writer).unwrap();
+            let mut res = data[..len].to_vec();
+            decrypt_data(&mut res, &key_stream, offset);
+            assert_eq!(res, expected);
+        }
     }
 }
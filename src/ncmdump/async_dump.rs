@@ -0,0 +1,319 @@
+//! NCM decoding over tokio's async IO.
+//!
+//! As with [`NcmDump`](super::NcmDump), positions are relative to the audio:
+//! seeking to 0 goes to its first byte.
+
+use super::error::{DumpResult, Error};
+use super::{decrypt_data, MediaFormat, NcmInfo, SNIFF_LEN};
+use crate::feed::{Event, Feed, NcmFeeder};
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+#[cfg(feature = "tag")]
+use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, ReadBuf};
+
+pub struct AsyncNcmDump<R> {
+    reader: R,
+    cursor: usize,
+    key_stream: Vec<u8>,
+    info: Option<NcmInfo>,
+    image: Vec<u8>,
+    data_start: u64,
+    seek: SeekState,
+}
+
+/// Progress of a seek started by [`AsyncSeek::start_seek`].
+#[derive(Clone, Copy)]
+enum SeekState {
+    Idle,
+    /// Moving the reader to this position of the audio
+    Seeking(u64),
+    /// Finding the end of the file, to resolve `SeekFrom::End` with this
+    /// offset
+    MeasuringEnd(i64),
+    /// Moving the reader back after a seek before the start of the audio
+    Restoring,
+}
+
+fn before_start() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "Seeked before the start of the audio",
+    )
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncNcmDump<R> {
+    /// Parse the header with [`NcmFeeder`], reading only as much as each
+    /// field needs. The info and the cover are kept in memory.
+    pub async fn from_reader(mut reader: R) -> DumpResult<Self> {
+        let mut feeder = NcmFeeder::new();
+        let mut info = None;
+        let mut image = vec![];
+
+        while let Some(needed) = feeder.header_needed() {
+            let mut buf = vec![];
+            (&mut reader)
+                .take(needed as u64)
+                .read_to_end(&mut buf)
+                .await?;
+            let mut events = feeder.feed(&buf);
+            if buf.len() < needed {
+                events.extend(feeder.finish());
+            }
+
+            for event in events {
+                match event {
+                    Event::Info(i) => info = Some(i),
+                    Event::Cover(i) => image = i,
+                    Event::Error(e) => return Err(e),
+                    Event::Header | Event::Audio(_) | Event::Finished => (),
+                }
+            }
+        }
+        let data_start = reader.stream_position().await?;
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            key_stream: feeder.into_key_stream(),
+            info,
+            image,
+            data_start,
+            seek: SeekState::Idle,
+        })
+    }
+
+    /// Read `len` raw bytes at `start` of the file, leaving the position as it
+    /// was.
+    async fn read_raw(&mut self, start: u64, len: u64) -> DumpResult<Vec<u8>> {
+        let original_pos = self.reader.stream_position().await?;
+        self.reader.seek(SeekFrom::Start(start)).await?;
+        let mut buf = vec![];
+        (&mut self.reader).take(len).read_to_end(&mut buf).await?;
+        self.reader.seek(SeekFrom::Start(original_pos)).await?;
+        Ok(buf)
+    }
+
+    pub async fn get_info(&mut self) -> DumpResult<NcmInfo> {
+        self.info.clone().ok_or(Error::InfoLoadError)
+    }
+
+    pub async fn get_image(&mut self) -> DumpResult<Vec<u8>> {
+        Ok(self.image.clone())
+    }
+
+    /// Container of the audio, sniffed from its first bytes. The format in
+    /// the info is only used when sniffing finds nothing.
+    pub async fn get_format(&mut self) -> DumpResult<MediaFormat> {
+        let mut head = self.read_raw(self.data_start, SNIFF_LEN as u64).await?;
+        decrypt_data(&mut head, &self.key_stream, 0);
+        match MediaFormat::sniff(&head) {
+            MediaFormat::Unknown => Ok(self.get_info().await?.format.as_str().into()),
+            format => Ok(format),
+        }
+    }
+
+    pub async fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(0)).await?;
+        Ok(())
+    }
+
+    pub async fn write_to(&mut self, writer: &mut (impl AsyncWrite + Unpin)) -> DumpResult<()> {
+        self.move_to_start().await?;
+        tokio::io::copy(self, writer).await?;
+        Ok(())
+    }
+
+    /// The tag writers are synchronous, so the audio is tagged in memory and
+    /// the result written out at once.
+    #[cfg(feature = "tag")]
+    pub async fn write_with_tag(
+        &mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> DumpResult<()> {
+        let info = self.get_info().await?;
+        let image = self.get_image().await?;
+        let format = self.get_format().await?;
+
+        self.move_to_start().await?;
+        let mut audio = vec![];
+        self.read_to_end(&mut audio).await?;
+
+        let mut tagged = std::io::Cursor::new(Vec::with_capacity(audio.len() + image.len()));
+        super::tag::write_with_info(&mut &audio[..], &mut tagged, &info, format, Some(&image))?;
+        writer.write_all(tagged.get_ref()).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncNcmDump<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.reader).poll_read(cx, buf))?;
+
+        let read = &mut buf.filled_mut()[filled..];
+        decrypt_data(read, &this.key_stream, this.cursor);
+        this.cursor += read.len();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncNcmDump<R> {
+    fn start_seek_audio(&mut self, pos: u64) -> std::io::Result<()> {
+        Pin::new(&mut self.reader).start_seek(SeekFrom::Start(self.data_start + pos))?;
+        self.seek = SeekState::Seeking(pos);
+        Ok(())
+    }
+
+    fn poll_seek(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        loop {
+            let reader_pos = ready!(Pin::new(&mut self.reader).poll_complete(cx))?;
+            match self.seek {
+                SeekState::Idle => return Poll::Ready(Ok(self.cursor as u64)),
+                SeekState::Seeking(pos) => {
+                    self.seek = SeekState::Idle;
+                    self.cursor = pos as usize;
+                    return Poll::Ready(Ok(pos));
+                }
+                SeekState::MeasuringEnd(offset) => {
+                    match reader_pos
+                        .checked_sub(self.data_start)
+                        .and_then(|audio_len| audio_len.checked_add_signed(offset))
+                    {
+                        Some(pos) => self.start_seek_audio(pos)?,
+                        None => {
+                            // Back where it was, as `End` moved the reader
+                            let cursor = self.data_start + self.cursor as u64;
+                            Pin::new(&mut self.reader).start_seek(SeekFrom::Start(cursor))?;
+                            self.seek = SeekState::Restoring;
+                        }
+                    }
+                }
+                SeekState::Restoring => {
+                    self.seek = SeekState::Idle;
+                    return Poll::Ready(Err(before_start()));
+                }
+            }
+        }
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for AsyncNcmDump<R> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        // Resolve the position in the audio first, so a seek before its
+        // start fails without moving the reader
+        match position {
+            SeekFrom::Start(pos) => this.start_seek_audio(pos),
+            SeekFrom::Current(offset) => match (this.cursor as u64).checked_add_signed(offset) {
+                Some(pos) => this.start_seek_audio(pos),
+                None => Err(before_start()),
+            },
+            SeekFrom::End(offset) => {
+                Pin::new(&mut this.reader).start_seek(SeekFrom::End(0))?;
+                this.seek = SeekState::MeasuringEnd(offset);
+                Ok(())
+            }
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        let res = ready!(this.poll_seek(cx));
+        if res.is_err() {
+            this.seek = SeekState::Idle;
+        }
+        Poll::Ready(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncNcmDump;
+    use crate::ncmdump::NcmDump;
+    use crate::MediaFormat;
+    use std::io::SeekFrom;
+    use tokio::fs::File;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    fn expected() -> NcmDump<std::fs::File> {
+        NcmDump::from_reader(std::fs::File::open("./tests/test.ncm").unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let file = File::open("./tests/test.ncm").await.unwrap();
+        let mut dump = AsyncNcmDump::from_reader(file).await.unwrap();
+        let mut sync_dump = expected();
+
+        assert_eq!(
+            dump.get_info().await.unwrap(),
+            sync_dump.get_info().unwrap()
+        );
+        assert_eq!(
+            dump.get_image().await.unwrap(),
+            sync_dump.get_image().unwrap()
+        );
+        assert_eq!(dump.get_format().await.unwrap(), MediaFormat::fLaC);
+
+        let mut expected = vec![];
+        sync_dump.write_to(&mut expected).unwrap();
+        let mut res = vec![];
+        dump.write_to(&mut res).await.unwrap();
+        assert!(res == expected);
+
+        let pos = dump.seek(SeekFrom::Start(1000)).await.unwrap();
+        assert_eq!(pos, 1000);
+        let mut res = [0u8; 300];
+        dump.read_exact(&mut res).await.unwrap();
+        assert_eq!(res, expected[1000..1300]);
+
+        let pos = dump.seek(SeekFrom::End(-10)).await.unwrap();
+        assert_eq!(pos as usize, expected.len() - 10);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_seek() {
+        let file = File::open("./tests/test.ncm").await.unwrap();
+        let mut dump = AsyncNcmDump::from_reader(file).await.unwrap();
+        let mut audio = vec![];
+        expected().write_to(&mut audio).unwrap();
+
+        dump.seek(SeekFrom::Start(1000)).await.unwrap();
+        assert!(dump.seek(SeekFrom::Current(-100000)).await.is_err());
+        let too_far = -(audio.len() as i64) - 1;
+        assert!(dump.seek(SeekFrom::End(too_far)).await.is_err());
+        assert_eq!(dump.stream_position().await.unwrap(), 1000);
+
+        let mut res = [0u8; 300];
+        dump.read_exact(&mut res).await.unwrap();
+        assert_eq!(res, audio[1000..1300]);
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "tag")]
+    async fn test_write_with_tag() {
+        let file = File::open("./tests/test.ncm").await.unwrap();
+        let mut dump = AsyncNcmDump::from_reader(file).await.unwrap();
+        let name = dump.get_info().await.unwrap().name;
+
+        let mut output = vec![];
+        dump.write_with_tag(&mut output).await.unwrap();
+        let tag = metaflac::Tag::read_from(&mut &output[..]).unwrap();
+        assert_eq!(tag.vorbis_comments().unwrap().title().unwrap(), &[name]);
+        assert!(tag.pictures().next().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_not_ncm() {
+        let file = std::io::Cursor::new(b"not an ncm file".to_vec());
+        assert!(AsyncNcmDump::from_reader(file).await.is_err());
+    }
+}
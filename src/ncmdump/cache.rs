@@ -0,0 +1,289 @@
+//! NetEase Cloud Music cache files: `.uc!` is the whole audio XORed with
+//! `0xA3`, described by a JSON sidecar (`.idx!` or `.info`) next to it.
+
+use super::error::{DumpResult, Error};
+use super::{MediaFormat, NcmInfo, SNIFF_LEN};
+use crate::decryptor::Decryptor;
+use serde_json::{Map, Value};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const KEY: u8 = 0xA3;
+
+const SIDECAR_EXTS: [&str; 2] = ["idx!", "info"];
+
+pub struct NcmCacheDump<R: Read> {
+    reader: R,
+    info: Option<NcmInfo>,
+}
+
+impl<R: Read + Seek> NcmCacheDump<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self { reader, info: None }
+    }
+
+    pub fn from_reader_with_info(reader: R, info: NcmInfo) -> Self {
+        Self {
+            reader,
+            info: Some(info),
+        }
+    }
+
+    pub fn set_info(&mut self, info: NcmInfo) {
+        self.info = Some(info);
+    }
+
+    /// The info read from the sidecar. When the sidecar does not state the
+    /// format, it is guessed from the audio.
+    pub fn get_info(&mut self) -> DumpResult<NcmInfo> {
+        let mut info = self.info.clone().ok_or(Error::InfoLoadError)?;
+        if info.format.is_empty() {
+            info.format = match self.get_format()? {
+                MediaFormat::fLaC => "flac",
+                MediaFormat::ID3v2 => "mp3",
+                MediaFormat::M4A => "m4a",
+                MediaFormat::WAV => "wav",
+                MediaFormat::Ogg => "ogg",
+                MediaFormat::Opus => "opus",
+                MediaFormat::AAC => "aac",
+                MediaFormat::APE => "ape",
+                MediaFormat::WavPack => "wv",
+                MediaFormat::DSF => "dsf",
+                _ => "",
+            }
+            .to_owned();
+        }
+        Ok(info)
+    }
+
+    /// Guess the format from the first bytes of the audio.
+    pub fn get_format(&mut self) -> DumpResult<MediaFormat> {
+        let original_pos = self.reader.stream_position()?;
+        let mut magic = vec![];
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.reader
+            .by_ref()
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut magic)?;
+        self.reader.seek(SeekFrom::Start(original_pos))?;
+        magic.iter_mut().for_each(|b| *b ^= KEY);
+
+        Ok(MediaFormat::sniff(&magic))
+    }
+
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+
+    /// Same as [`super::NcmDump::write_with_tag`], without a cover since the
+    /// cache keeps none.
+    #[cfg(feature = "tag")]
+    pub fn write_with_tag(&mut self, writer: &mut (impl Write + Seek)) -> DumpResult<()> {
+        let info = self.get_info()?;
+        let format = Decryptor::format(self)?;
+        self.move_to_start()?;
+
+        super::tag::write_with_info(self, writer, &info, format, None)
+    }
+}
+
+impl NcmCacheDump<std::fs::File> {
+    /// Open a `.uc!` file along with the first sidecar found next to it.
+    pub fn open(path: &Path) -> DumpResult<Self> {
+        let mut dump = Self::from_reader(std::fs::File::open(path)?);
+        if let Some(sidecar) = find_sidecar(path) {
+            dump.set_info(parse_info(&std::fs::read_to_string(sidecar)?)?);
+        }
+        Ok(dump)
+    }
+}
+
+fn find_sidecar(path: &Path) -> Option<PathBuf> {
+    SIDECAR_EXTS
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|p| p.is_file())
+}
+
+fn get<'a>(obj: &'a Map<String, Value>, keys: &[&str]) -> Option<&'a Value> {
+    keys.iter()
+        .find_map(|k| obj.get(*k))
+        .filter(|v| !v.is_null())
+}
+
+fn as_u64(value: &Value) -> Option<u64> {
+    value
+        .as_u64()
+        .or_else(|| value.as_f64().map(|f| f as u64))
+        .or_else(|| value.as_str()?.parse().ok())
+}
+
+/// An artist entry is either `[name, id]`, `{"name": .., "id": ..}` or a
+/// bare name.
+fn parse_artist(value: &Value) -> Option<(String, u64)> {
+    match value {
+        Value::String(name) => Some((name.clone(), 0)),
+        Value::Array(pair) => Some((
+            pair.first()?.as_str()?.to_owned(),
+            pair.get(1).and_then(as_u64).unwrap_or(0),
+        )),
+        Value::Object(obj) => Some((
+            get(obj, &["name"])?.as_str()?.to_owned(),
+            get(obj, &["id"]).and_then(as_u64).unwrap_or(0),
+        )),
+        _ => None,
+    }
+}
+
+/// Build an [`NcmInfo`] from a cache sidecar. The client has written these
+/// under several key spellings over the years; missing fields are left empty.
+pub fn parse_info(json: &str) -> DumpResult<NcmInfo> {
+    let value: Value = serde_json::from_str(json).map_err(|_| Error::InfoDecodeError)?;
+    let obj = value.as_object().ok_or(Error::InfoDecodeError)?;
+
+    let text = |keys: &[&str]| {
+        get(obj, keys)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_owned()
+    };
+    let number = |keys: &[&str]| get(obj, keys).and_then(as_u64);
+
+    let artist = match get(obj, &["artist", "artists", "artistName"]) {
+        Some(Value::Array(artists)) => artists.iter().filter_map(parse_artist).collect(),
+        Some(value) => parse_artist(value).into_iter().collect(),
+        None => vec![],
+    };
+
+    Ok(NcmInfo {
+        name: text(&["musicName", "songName", "name"]),
+        id: number(&["musicId", "musicid", "songId", "id"]).unwrap_or(0),
+        album: text(&["album", "albumName"]),
+        artist,
+        bitrate: number(&["bitrate", "br"]).unwrap_or(0),
+        duration: number(&["duration"]).unwrap_or(0),
+        format: text(&["format"]).to_ascii_lowercase(),
+        mv_id: number(&["mvId"]),
+        alias: get(obj, &["alias"]).and_then(|v| {
+            v.as_array().map(|a| {
+                a.iter()
+                    .filter_map(|s| s.as_str().map(|s| s.to_owned()))
+                    .collect()
+            })
+        }),
+    })
+}
+
+impl<R: Read + Seek> Decryptor for NcmCacheDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(match &self.info {
+            Some(info) if !info.format.is_empty() => info.format.as_str().into(),
+            _ => MediaFormat::Unknown,
+        })
+    }
+
+    fn sniffed_format(&mut self) -> DumpResult<MediaFormat> {
+        self.get_format()
+    }
+
+    fn info(&mut self) -> DumpResult<Option<NcmInfo>> {
+        match self.info {
+            Some(_) => self.get_info().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R: Read> Read for NcmCacheDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        buf[..size].iter_mut().for_each(|b| *b ^= KEY);
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for NcmCacheDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_info, NcmCacheDump, KEY};
+    use crate::ncmdump::NcmDump;
+    use crate::MediaFormat;
+    use std::fs::File;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    fn cache_file() -> (Vec<u8>, Vec<u8>) {
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut audio = vec![];
+        dump.write_to(&mut audio).unwrap();
+        let cache = audio.iter().map(|b| b ^ KEY).collect();
+        (audio, cache)
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = parse_info(
+            r#"{"musicId": "1372188635", "musicName": "Song", "album": "Album",
+                "artist": [["A", 1], ["B", 2]], "bitrate": 999000, "duration": 1234,
+                "format": "FLAC", "alias": ["Alias"]}"#,
+        )
+        .unwrap();
+        assert_eq!(info.id, 1372188635);
+        assert_eq!(info.name, "Song");
+        assert_eq!(info.artist, [("A".into(), 1), ("B".into(), 2)]);
+        assert_eq!(info.format, "flac");
+        assert_eq!(info.alias, Some(vec!["Alias".into()]));
+
+        let info = parse_info(r#"{"songId": 42, "artists": [{"name": "C", "id": 3}]}"#).unwrap();
+        assert_eq!(info.id, 42);
+        assert_eq!(info.artist, [("C".into(), 3)]);
+        assert!(info.name.is_empty());
+
+        assert!(parse_info("[]").is_err());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let (audio, cache) = cache_file();
+        let mut dump = NcmCacheDump::from_reader(Cursor::new(cache));
+        assert!(matches!(dump.get_format().unwrap(), MediaFormat::fLaC));
+        assert!(dump.get_info().is_err());
+
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio);
+
+        dump.seek(SeekFrom::Start(1000)).unwrap();
+        let mut res = [0u8; 16];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(res[..], audio[1000..1016]);
+    }
+
+    #[test]
+    #[cfg(feature = "tag")]
+    fn test_write_with_tag() {
+        let (_, cache) = cache_file();
+        let info = parse_info(r#"{"musicId": 1, "musicName": "Cached", "artist": "X"}"#).unwrap();
+        let mut dump = NcmCacheDump::from_reader_with_info(Cursor::new(cache), info);
+        assert_eq!(dump.get_info().unwrap().format, "flac");
+
+        let mut output = Cursor::new(vec![]);
+        dump.write_with_tag(&mut output).unwrap();
+        output.set_position(0);
+        let tag = metaflac::Tag::read_from(&mut output).unwrap();
+        let comments = tag.vorbis_comments().unwrap();
+        assert_eq!(comments.title().unwrap(), &["Cached"]);
+        assert_eq!(comments.artist().unwrap(), &["X"]);
+    }
+}
@@ -0,0 +1,356 @@
+//! [`NcmDump`], the NCM decoder over a seekable reader.
+
+use super::error::DumpResult;
+use super::feed::{Field, NcmFeeder};
+use super::{decode_info, decrypt_data, MediaFormat, NcmInfo};
+use crate::decryptor::Decryptor;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub struct NcmDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    key_stream: Vec<u8>,
+    info_range: (u64, u64),
+    image_range: (u64, u64),
+    data_start: u64,
+}
+
+impl<R: Read + Seek> NcmDump<R> {
+    /// Walk the header with [`NcmFeeder`], remembering where the info and
+    /// the cover are so that they are only read when asked for.
+    pub fn from_reader(mut reader: R) -> DumpResult<Self> {
+        let mut feeder = NcmFeeder::new();
+        let mut info_range = (0, 0);
+        let mut image_range = (0, 0);
+
+        let mut field = vec![];
+        while let Some(needed) = feeder.header_needed() {
+            let start = reader.stream_position()?;
+            field.clear();
+            reader
+                .by_ref()
+                .take(needed as u64)
+                .read_to_end(&mut field)?;
+            if field.len() < needed {
+                return Err(feeder.truncated());
+            }
+
+            match feeder.read_field(&field)? {
+                Field::Info => info_range = (start, needed as u64),
+                Field::Image => image_range = (start, needed as u64),
+                Field::Key | Field::Other => (),
+            }
+        }
+        let data_start = reader.stream_position()?;
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            key_stream: feeder.into_key_stream(),
+            info_range,
+            image_range,
+            data_start,
+        })
+    }
+
+    pub fn get_info(&mut self) -> DumpResult<NcmInfo> {
+        let original_pos = self.reader.stream_position()?;
+
+        self.reader.seek(SeekFrom::Start(self.info_range.0))?;
+        let mut info_reader = self.reader.by_ref().take(self.info_range.1);
+        let mut info_buf = vec![];
+        let _ = info_reader.read_to_end(&mut info_buf)?;
+        self.reader.seek(SeekFrom::Start(original_pos))?;
+
+        decode_info(info_buf)
+    }
+
+    pub fn get_image(&mut self) -> DumpResult<Vec<u8>> {
+        let original_pos = self.reader.stream_position()?;
+
+        self.reader.seek(SeekFrom::Start(self.image_range.0))?;
+        let mut image_reader = self.reader.by_ref().take(self.image_range.1);
+        let mut image_buf = vec![];
+        let _ = image_reader.read_to_end(&mut image_buf)?;
+        self.reader.seek(SeekFrom::Start(original_pos))?;
+
+        Ok(image_buf)
+    }
+
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+
+    /// Container of the audio, sniffed from its first bytes. The format in
+    /// the info is only used when sniffing finds nothing.
+    pub fn get_format(&mut self) -> DumpResult<MediaFormat> {
+        Decryptor::format(self)
+    }
+
+    #[cfg(feature = "tag")]
+    pub fn write_with_tag(&mut self, writer: &mut (impl Write + Seek)) -> DumpResult<()> {
+        let info = self.get_info()?;
+        let image = self.get_image()?;
+        let format = self.get_format()?;
+        self.move_to_start()?;
+
+        super::tag::write_with_info(self, writer, &info, format, Some(&image))
+    }
+}
+
+impl<R: Read + Seek> Decryptor for NcmDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.get_info()?.format.as_str().into())
+    }
+
+    fn info(&mut self) -> DumpResult<Option<NcmInfo>> {
+        self.get_info().map(Some)
+    }
+
+    fn cover(&mut self) -> DumpResult<Option<Vec<u8>>> {
+        let image = self.get_image()?;
+        Ok((!image.is_empty()).then_some(image))
+    }
+}
+
+impl<R: Read> Read for NcmDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        decrypt_data(&mut buf[..size], &self.key_stream, self.cursor);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+/// Positions are relative to the audio, as if it were a plain audio file:
+/// seeking to 0 goes to its first byte, and the end is the end of the audio.
+impl<R: Read + Seek> Seek for NcmDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => (self.cursor as u64).checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                let audio_len = self.reader.seek(SeekFrom::End(0))? - self.data_start;
+                audio_len.checked_add_signed(offset)
+            }
+        };
+        let Some(new_pos) = new_pos else {
+            // Back where it was, in case `End` moved the reader
+            self.reader
+                .seek(SeekFrom::Start(self.data_start + self.cursor as u64))?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked before the start of the audio",
+            ));
+        };
+
+        self.reader
+            .seek(SeekFrom::Start(self.data_start + new_pos))?;
+        self.cursor = new_pos as usize;
+        Ok(new_pos)
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.cursor as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NcmDump;
+    use crate::ncmdump::error::Error;
+    use crate::ncmdump::{MediaFormat, NcmInfo};
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_build_from_ncm_file() {
+        let reader = File::open("./tests/test.ncm").unwrap();
+        let _ = NcmDump::from_reader(reader).unwrap();
+    }
+
+    /// Hands out one byte per read, like a slow pipe.
+    struct Trickle(File);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    impl std::io::Seek for Trickle {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_build_from_short_reads() {
+        let reader = Trickle(File::open("./tests/test.ncm").unwrap());
+        let mut dump = NcmDump::from_reader(reader).unwrap();
+        assert!(matches!(dump.get_format().unwrap(), MediaFormat::fLaC));
+    }
+
+    #[test]
+    fn test_build_from_truncated() {
+        let file = std::fs::read("./tests/test.ncm").unwrap();
+        for (len, error) in [
+            (8, Error::FormatError),
+            (200, Error::InfoLoadError),
+            (1000, Error::ImageLoadError),
+        ] {
+            let res = NcmDump::from_reader(Cursor::new(&file[..len]));
+            assert_eq!(res.err().map(|e| e.to_string()), Some(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_get_format() {
+        let reader = File::open("./tests/test.ncm").unwrap();
+        let mut dump = NcmDump::from_reader(reader).unwrap();
+        assert_eq!(dump.get_format().unwrap(), MediaFormat::fLaC);
+    }
+
+    #[test]
+    fn test_decrypt_file() {
+        let reader = File::open("./tests/test.ncm").unwrap();
+        let mut dump = NcmDump::from_reader(reader).unwrap();
+
+        let mut data: Vec<u8> = vec![];
+        let size = dump.read_to_end(&mut data).unwrap();
+
+        assert_eq!(size, 61440);
+        assert_eq!(
+            data[0..16],
+            [
+                0x66, 0x4c, 0x61, 0x43, 0x00, 0x00, 0x00, 0x22, 0x12, 0x00, 0x12, 0x00, 0x00, 0x01,
+                0x01, 0x00,
+            ]
+        );
+        assert_eq!(
+            data[61424..],
+            [
+                0x8b, 0x25, 0x88, 0x08, 0x4b, 0x49, 0x89, 0xc2, 0xba, 0xe3, 0xda, 0x88, 0x48, 0xc1,
+                0x09, 0x7b,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_info() {
+        let reader = File::open("./tests/test.ncm").unwrap();
+        let mut dump = NcmDump::from_reader(reader).unwrap();
+
+        let info = dump.get_info().unwrap();
+        assert_eq!(
+            info,
+            NcmInfo {
+                name: "寒鸦少年".to_string(),
+                id: 1305366556,
+                album: "寒鸦少年".to_string(),
+                artist: vec![("华晨宇".into(), 861777)],
+                bitrate: 923378,
+                duration: 315146,
+                format: "flac".to_string(),
+                mv_id: Some(0),
+                alias: Some(vec!["电视剧《斗破苍穹》主题曲".into()]),
+            },
+        )
+    }
+
+    #[test]
+    fn test_get_image() {
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+
+        let image = dump.get_image().unwrap();
+        assert_eq!(image.len(), 39009);
+        assert_eq!(
+            image[..16],
+            [
+                0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10, 0x4a, 0x46, 0x49, 0x46, 0x00, 0x01, 0x01, 0x01,
+                0x00, 0x48,
+            ],
+        );
+        assert_eq!(
+            image[38993..],
+            [
+                0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20,
+                0xff, 0xd9,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_seek() {
+        use std::io::{Seek, SeekFrom};
+
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut expected = vec![];
+        dump.write_to(&mut expected).unwrap();
+        assert_eq!(dump.stream_position().unwrap() as usize, expected.len());
+
+        assert_eq!(dump.seek(SeekFrom::Start(0)).unwrap(), 0);
+        let mut buf = [0u8; 4];
+        dump.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"fLaC");
+        assert_eq!(dump.stream_position().unwrap(), 4);
+
+        assert_eq!(dump.seek(SeekFrom::Current(996)).unwrap(), 1000);
+        dump.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[1000..1004]);
+
+        let end = dump.seek(SeekFrom::End(-4)).unwrap();
+        assert_eq!(end as usize, expected.len() - 4);
+        dump.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[expected.len() - 4..]);
+
+        assert!(dump.seek(SeekFrom::Current(-100000)).is_err());
+        assert!(dump.seek(SeekFrom::End(-100000)).is_err());
+        assert_eq!(dump.stream_position().unwrap() as usize, expected.len());
+        assert_eq!(dump.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_decrypt_digest() {
+        use md5::{Digest, Md5};
+
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut data = vec![];
+        dump.write_to(&mut data).unwrap();
+        assert_eq!(data.len(), 61440);
+        assert_eq!(
+            format!("{:x}", Md5::digest(&data)),
+            "4bd8ab4dc6d7699291f9af485b8a4115"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tag")]
+    fn test_write_with_tag() {
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut writer = File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open("./tests/test.flac")
+            .unwrap();
+        dump.write_to(&mut writer).unwrap();
+
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut writer = File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open("./tests/test_tagged.flac")
+            .unwrap();
+        dump.write_with_tag(&mut writer).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use alloc::string::String;
 use thiserror::Error;
 
 pub type DumpResult<T> = Result<T, Error>;
@@ -28,6 +28,20 @@ pub enum Error {
 
     #[error("Cannot decrypt the key")]
     KeyDecryptError,
+    #[error("Cannot read the QMC key trailer")]
+    TrailerError,
+    #[error("Cannot decode the QMC ekey")]
+    EKeyDecodeError,
+    #[error("Cannot parse the MMKV file")]
+    MmkvError,
+    #[error("Unsupported crypto version")]
+    CryptoVersionError,
+    #[error("Cannot read the key database")]
+    DatabaseError,
+    #[error("Cannot find the key of the KGG file")]
+    KggKeyError,
+    #[error("Cannot decrypt the Joox block")]
+    BlockDecryptError,
 
     #[error("Cannot build the tag: {0}")]
     TagBuildError(String),
@@ -38,8 +52,9 @@ pub enum Error {
     IO(String),
 }
 
-impl From<io::Error> for Error {
-    fn from(value: io::Error) -> Self {
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
         Self::IO(value.to_string())
     }
 }
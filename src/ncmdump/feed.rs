@@ -0,0 +1,256 @@
+//! The NCM format as a state machine over pushed bytes. It is also the one
+//! description of the header that every NCM decoder walks.
+
+use super::error::Error;
+use super::{
+    build_key_box_from_encrypted, build_key_stream, check_format, decode_info, decrypt_data,
+};
+use crate::feed::{Event, Feed};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Magic number and the 2-byte gap after it
+const MAGIC_LEN: usize = 10;
+/// CRC and the unknown bytes between the info and the cover
+const GAP_LEN: usize = 9;
+
+/// A header field, as told by [`NcmFeeder::read_field`].
+pub(crate) enum Field {
+    Key,
+    Info,
+    Image,
+    Other,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Magic,
+    KeyLength,
+    Key(usize),
+    InfoLength,
+    Info(usize),
+    Gap,
+    ImageLength,
+    Image(usize),
+    Audio,
+    Done,
+}
+
+impl State {
+    /// Bytes needed before leaving this state.
+    fn needed(&self) -> usize {
+        match self {
+            Self::Magic => MAGIC_LEN,
+            Self::KeyLength | Self::InfoLength | Self::ImageLength => 4,
+            Self::Key(len) | Self::Info(len) | Self::Image(len) => *len,
+            Self::Gap => GAP_LEN,
+            Self::Audio | Self::Done => 0,
+        }
+    }
+
+    /// The error of an input that ends in this state.
+    fn truncated(&self) -> Error {
+        match self {
+            Self::Magic => Error::FormatError,
+            Self::KeyLength => Error::KeyLengthError,
+            Self::Key(_) => Error::KeyLoadError,
+            Self::InfoLength => Error::InfoLengthError,
+            Self::Info(_) => Error::InfoLoadError,
+            Self::Gap | Self::ImageLength => Error::ImageLengthError,
+            Self::Image(_) | Self::Audio | Self::Done => Error::ImageLoadError,
+        }
+    }
+}
+
+pub struct NcmFeeder {
+    state: State,
+    /// Bytes of the current header field
+    pending: Vec<u8>,
+    key_stream: Vec<u8>,
+    cursor: usize,
+}
+
+impl Default for NcmFeeder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn length(buf: &[u8]) -> usize {
+    u32::from_le_bytes(buf.try_into().unwrap()) as usize
+}
+
+impl NcmFeeder {
+    pub fn new() -> Self {
+        Self {
+            state: State::Magic,
+            pending: vec![],
+            key_stream: vec![],
+            cursor: 0,
+        }
+    }
+
+    /// Whether the header is over and the rest is audio.
+    pub fn in_audio(&self) -> bool {
+        matches!(self.state, State::Audio)
+    }
+
+    /// Bytes still needed to finish the current header field, or `None` once
+    /// the header is over.
+    pub(crate) fn header_needed(&self) -> Option<usize> {
+        match self.state {
+            State::Audio | State::Done => None,
+            state => Some(state.needed() - self.pending.len()),
+        }
+    }
+
+    /// The error of a header that ends before the current field does.
+    pub(crate) fn truncated(&self) -> Error {
+        self.state.truncated()
+    }
+
+    pub(crate) fn into_key_stream(self) -> Vec<u8> {
+        self.key_stream
+    }
+
+    /// Leave the current state with `field`, all of its bytes, and tell
+    /// which field it was, for decoders that walk the header field by field
+    /// instead of feeding it.
+    pub(crate) fn read_field(&mut self, field: &[u8]) -> Result<Field, Error> {
+        let (state, kind) = match self.state {
+            State::Magic if check_format(field) => (State::KeyLength, Field::Other),
+            State::Magic => return Err(Error::FormatError),
+            State::KeyLength => (State::Key(length(field)), Field::Other),
+            State::Key(_) => {
+                self.key_stream = build_key_stream(&build_key_box_from_encrypted(field)?);
+                (State::InfoLength, Field::Key)
+            }
+            State::InfoLength => (State::Info(length(field)), Field::Other),
+            State::Info(_) => (State::Gap, Field::Info),
+            State::Gap => (State::ImageLength, Field::Other),
+            State::ImageLength => (State::Image(length(field)), Field::Other),
+            State::Image(_) => (State::Audio, Field::Image),
+            state @ (State::Audio | State::Done) => (state, Field::Other),
+        };
+        self.state = state;
+        Ok(kind)
+    }
+
+    /// Leave the state whose field is in `pending`.
+    fn advance(&mut self, events: &mut Vec<Event>) -> Result<(), Error> {
+        let field = core::mem::take(&mut self.pending);
+        match self.read_field(&field)? {
+            Field::Key => events.push(Event::Header),
+            Field::Info if !field.is_empty() => events.push(Event::Info(decode_info(field)?)),
+            Field::Image if !field.is_empty() => events.push(Event::Cover(field)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn feed_inner(&mut self, mut data: &[u8], events: &mut Vec<Event>) -> Result<(), Error> {
+        loop {
+            match self.state {
+                State::Done => return Ok(()),
+                State::Audio => {
+                    if !data.is_empty() {
+                        let mut audio = data.to_vec();
+                        decrypt_data(&mut audio, &self.key_stream, self.cursor);
+                        self.cursor += audio.len();
+                        events.push(Event::Audio(audio));
+                    }
+                    return Ok(());
+                }
+                state => {
+                    let needed = state.needed() - self.pending.len();
+                    let len = needed.min(data.len());
+                    self.pending.extend(&data[..len]);
+                    data = &data[len..];
+                    if len < needed {
+                        return Ok(());
+                    }
+                    self.advance(events)?;
+                }
+            }
+        }
+    }
+}
+
+impl Feed for NcmFeeder {
+    fn feed(&mut self, data: &[u8]) -> Vec<Event> {
+        let mut events = vec![];
+        if let Err(e) = self.feed_inner(data, &mut events) {
+            self.state = State::Done;
+            events.push(Event::Error(e));
+        }
+        events
+    }
+
+    fn finish(&mut self) -> Vec<Event> {
+        let event = match self.state {
+            State::Done => return vec![],
+            State::Audio => Event::Finished,
+            state => Event::Error(state.truncated()),
+        };
+        self.state = State::Done;
+        vec![event]
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::NcmFeeder;
+    use crate::feed::{Event, Feed};
+    use crate::ncmdump::NcmDump;
+    use std::fs::File;
+
+    #[test]
+    fn test_feed_byte_by_byte() {
+        let file = std::fs::read("./tests/test.ncm").unwrap();
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+
+        let mut feeder = NcmFeeder::new();
+        let mut events = vec![];
+        for byte in &file {
+            events.extend(feeder.feed(std::slice::from_ref(byte)));
+        }
+        events.extend(feeder.finish());
+        assert!(feeder.finish().is_empty());
+
+        assert!(matches!(events[0], Event::Header));
+        match &events[1] {
+            Event::Info(info) => assert_eq!(info, &dump.get_info().unwrap()),
+            e => panic!("{:?}", e),
+        }
+        match &events[2] {
+            Event::Cover(cover) => assert!(cover == &dump.get_image().unwrap()),
+            e => panic!("{:?}", e),
+        }
+        assert!(matches!(events.last(), Some(Event::Finished)));
+
+        let mut audio: Vec<u8> = vec![];
+        for event in &events[3..events.len() - 1] {
+            match event {
+                Event::Audio(chunk) => audio.extend(chunk),
+                e => panic!("{:?}", e),
+            }
+        }
+        let mut expected = vec![];
+        dump.write_to(&mut expected).unwrap();
+        assert!(audio == expected);
+    }
+
+    #[test]
+    fn test_feed_errors() {
+        let mut feeder = NcmFeeder::new();
+        let events = feeder.feed(b"not an ncm file");
+        assert!(matches!(events[..], [Event::Error(_)]));
+        assert!(feeder.feed(b"more").is_empty());
+        assert!(feeder.finish().is_empty());
+
+        let file = std::fs::read("./tests/test.ncm").unwrap();
+        let mut feeder = NcmFeeder::new();
+        feeder.feed(&file[..100]);
+        assert!(matches!(feeder.finish()[..], [Event::Error(_)]));
+    }
+}
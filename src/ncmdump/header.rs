@@ -0,0 +1,151 @@
+//! The NCM header parsed straight from the bytes of a whole file, for callers
+//! that already hold it in memory. Nothing is copied but the key: the info,
+//! the cover and the audio stay where they are, and the audio is decrypted in
+//! place.
+
+use super::error::{DumpResult, Error};
+use super::feed::{Field, NcmFeeder};
+use super::{decode_info, decrypt_data, NcmInfo};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+pub struct NcmHeader {
+    key_stream: Vec<u8>,
+    info_range: Range<usize>,
+    image_range: Range<usize>,
+    audio_range: Range<usize>,
+}
+
+/// Split the next `len` bytes off `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize, error: Error) -> DumpResult<&'a [u8]> {
+    if data.len() < len {
+        return Err(error);
+    }
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Ok(head)
+}
+
+impl NcmHeader {
+    /// Parse the header at the start of `file`, the whole NCM file.
+    pub fn parse(file: &[u8]) -> DumpResult<Self> {
+        let mut data = file;
+        let pos = |data: &[u8]| file.len() - data.len();
+
+        let mut feeder = NcmFeeder::new();
+        let mut info_range = 0..0;
+        let mut image_range = 0..0;
+        while let Some(needed) = feeder.header_needed() {
+            let start = pos(data);
+            let field = take(&mut data, needed, feeder.truncated())?;
+            match feeder.read_field(field)? {
+                Field::Info => info_range = start..pos(data),
+                Field::Image => image_range = start..pos(data),
+                Field::Key | Field::Other => (),
+            }
+        }
+
+        Ok(Self {
+            key_stream: feeder.into_key_stream(),
+            info_range,
+            image_range,
+            audio_range: pos(data)..file.len(),
+        })
+    }
+
+    /// Decode the info of `file`, the one this header was parsed from.
+    pub fn info(&self, file: &[u8]) -> DumpResult<NcmInfo> {
+        decode_info(file[self.info_range.clone()].to_vec())
+    }
+
+    pub fn image_range(&self) -> Range<usize> {
+        self.image_range.clone()
+    }
+
+    /// Where the encrypted audio is in the file.
+    pub fn audio_range(&self) -> Range<usize> {
+        self.audio_range.clone()
+    }
+
+    /// Decrypt `buf` in place, audio that starts at `offset` of the audio.
+    pub fn decrypt(&self, buf: &mut [u8], offset: usize) {
+        decrypt_data(buf, &self.key_stream, offset);
+    }
+
+    /// Decrypt the whole audio of `file` in place and return it.
+    pub fn decrypt_audio<'a>(&self, file: &'a mut [u8]) -> &'a mut [u8] {
+        let audio = &mut file[self.audio_range.clone()];
+        self.decrypt(audio, 0);
+        audio
+    }
+
+    /// Tag the audio of `file`, already decrypted with
+    /// [`decrypt_audio`](Self::decrypt_audio), and write it out.
+    #[cfg(feature = "tag")]
+    pub fn write_with_tag(
+        &self,
+        file: &[u8],
+        writer: &mut (impl std::io::Write + std::io::Seek),
+    ) -> DumpResult<()> {
+        let info = self.info(file)?;
+        let audio = &file[self.audio_range()];
+        let format = match super::MediaFormat::sniff(audio) {
+            super::MediaFormat::Unknown => info.format.as_str().into(),
+            format => format,
+        };
+        let image = &file[self.image_range()];
+        super::tag::write_with_info(&mut &*audio, writer, &info, format, Some(image))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::NcmHeader;
+    use crate::ncmdump::NcmDump;
+    use std::fs::File;
+
+    #[test]
+    fn test_parse() {
+        let mut file = std::fs::read("./tests/test.ncm").unwrap();
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut expected = vec![];
+        dump.write_to(&mut expected).unwrap();
+
+        let header = NcmHeader::parse(&file).unwrap();
+        assert_eq!(header.info(&file).unwrap(), dump.get_info().unwrap());
+        assert!(file[header.image_range()] == dump.get_image().unwrap());
+        assert_eq!(header.audio_range().len(), expected.len());
+
+        // In pieces, at their own offsets
+        let mut audio = file[header.audio_range()].to_vec();
+        for (index, chunk) in audio.chunks_mut(1000).enumerate() {
+            header.decrypt(chunk, index * 1000);
+        }
+        assert!(audio == expected);
+
+        assert!(header.decrypt_audio(&mut file) == &expected[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "tag")]
+    fn test_write_with_tag() {
+        let mut file = std::fs::read("./tests/test.ncm").unwrap();
+        let header = NcmHeader::parse(&file).unwrap();
+        let name = header.info(&file).unwrap().name;
+        header.decrypt_audio(&mut file);
+
+        let mut output = std::io::Cursor::new(vec![]);
+        header.write_with_tag(&file, &mut output).unwrap();
+        output.set_position(0);
+        let tag = metaflac::Tag::read_from(&mut output).unwrap();
+        assert_eq!(tag.vorbis_comments().unwrap().title().unwrap(), &[name]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let file = std::fs::read("./tests/test.ncm").unwrap();
+        assert!(NcmHeader::parse(b"CTENFDAM").is_err());
+        assert!(NcmHeader::parse(&file[..200]).is_err());
+        assert!(NcmHeader::parse(&file[..1000]).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! NCM decoding for input that can only be read forward, such as a pipe or a
+//! network stream. The info and the cover come before the audio, so they are
+//! buffered while the header is parsed.
+
+use super::error::{DumpResult, Error};
+use super::{MediaFormat, NcmInfo, SNIFF_LEN};
+use crate::feed::{Event, Feed, NcmFeeder};
+use std::io::{Read, Write};
+
+const CHUNK_LEN: usize = 0x2000;
+
+pub struct NcmStreamDump<R: Read> {
+    reader: R,
+    feeder: NcmFeeder,
+    info: Option<NcmInfo>,
+    image: Vec<u8>,
+    sniffed: MediaFormat,
+    /// Decrypted audio not read yet
+    audio: Vec<u8>,
+    audio_pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> NcmStreamDump<R> {
+    /// Read the header up to the first bytes of the audio, without seeking.
+    pub fn from_reader(reader: R) -> DumpResult<Self> {
+        let mut dump = Self {
+            reader,
+            feeder: NcmFeeder::new(),
+            info: None,
+            image: vec![],
+            sniffed: MediaFormat::Unknown,
+            audio: vec![],
+            audio_pos: 0,
+            finished: false,
+        };
+        while !dump.finished && dump.audio.len() < SNIFF_LEN {
+            dump.pump()?;
+        }
+        dump.sniffed = MediaFormat::sniff(&dump.audio);
+        Ok(dump)
+    }
+
+    /// Feed the next chunk of input to the decoder.
+    fn pump(&mut self) -> DumpResult<()> {
+        let mut buf = [0u8; CHUNK_LEN];
+        let size = self.reader.read(&mut buf)?;
+        let events = match size {
+            0 => self.feeder.finish(),
+            size => self.feeder.feed(&buf[..size]),
+        };
+
+        for event in events {
+            match event {
+                Event::Header => (),
+                Event::Info(info) => self.info = Some(info),
+                Event::Cover(image) => self.image = image,
+                Event::Audio(audio) => self.audio.extend(audio),
+                Event::Finished => self.finished = true,
+                Event::Error(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_info(&self) -> DumpResult<NcmInfo> {
+        self.info.clone().ok_or(Error::InfoLoadError)
+    }
+
+    pub fn get_image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Container of the audio, sniffed from its first bytes. The format in
+    /// the info is only used when sniffing finds nothing.
+    pub fn get_format(&self) -> DumpResult<MediaFormat> {
+        match self.sniffed {
+            MediaFormat::Unknown => Ok(self.get_info()?.format.as_str().into()),
+            format => Ok(format),
+        }
+    }
+
+    /// Write the audio that has not been read yet. The input cannot go back,
+    /// so this writes all of it only before any other read.
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tag")]
+    pub fn write_with_tag(&mut self, writer: &mut (impl Write + std::io::Seek)) -> DumpResult<()> {
+        let info = self.get_info()?;
+        let format = self.get_format()?;
+        let image = std::mem::take(&mut self.image);
+        let res = super::tag::write_with_info(self, writer, &info, format, Some(&image));
+        self.image = image;
+        res
+    }
+}
+
+impl<R: Read> Read for NcmStreamDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.audio_pos == self.audio.len() && !self.finished {
+            self.audio.clear();
+            self.audio_pos = 0;
+            self.pump()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+
+        let size = buf.len().min(self.audio.len() - self.audio_pos);
+        buf[..size].copy_from_slice(&self.audio[self.audio_pos..self.audio_pos + size]);
+        self.audio_pos += size;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NcmStreamDump;
+    use crate::ncmdump::NcmDump;
+    use crate::MediaFormat;
+    use std::fs::File;
+    use std::io::Read;
+
+    /// A reader that cannot seek, like a pipe.
+    struct Pipe(File);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            // Short reads, to cross the read-ahead in odd places
+            let len = buf.len().min(1000);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let pipe = Pipe(File::open("./tests/test.ncm").unwrap());
+        let mut stream = NcmStreamDump::from_reader(pipe).unwrap();
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+
+        assert_eq!(stream.get_info().unwrap(), dump.get_info().unwrap());
+        assert_eq!(stream.get_image(), dump.get_image().unwrap());
+        assert_eq!(stream.get_format().unwrap(), MediaFormat::fLaC);
+
+        let mut expected = vec![];
+        dump.write_to(&mut expected).unwrap();
+        let mut res = vec![];
+        stream.write_to(&mut res).unwrap();
+        assert!(res == expected);
+    }
+
+    #[test]
+    #[cfg(feature = "tag")]
+    fn test_write_with_tag() {
+        let pipe = Pipe(File::open("./tests/test.ncm").unwrap());
+        let mut stream = NcmStreamDump::from_reader(pipe).unwrap();
+        let name = stream.get_info().unwrap().name;
+
+        let mut output = std::io::Cursor::new(vec![]);
+        stream.write_with_tag(&mut output).unwrap();
+        output.set_position(0);
+        let tag = metaflac::Tag::read_from(&mut output).unwrap();
+        assert_eq!(tag.vorbis_comments().unwrap().title().unwrap(), &[name]);
+        assert!(tag.pictures().next().is_some());
+    }
+}
@@ -1,6 +1,33 @@
 use super::error::{DumpResult, Error};
+use super::{MediaFormat, NcmInfo};
+use audiotags::{AudioTagEdit, FlacTag, Id3v2Tag, Picture};
 use id3::Tag as ID3v2InnerTag;
 use metaflac::Tag as FlacInnerTag;
+use std::io::{Read, Seek, Write};
+
+pub(crate) mod ape;
+pub(crate) mod dsf;
+pub(crate) mod mp4;
+pub(crate) mod ogg;
+pub(crate) mod riff;
+
+macro_rules! write_tag {
+    ($tag:ty, $inner_tag:ty, $reader:ident, $writer:ident, $info:ident, $cover:ident) => {{
+        #[allow(deprecated)]
+        let inner_tag = <$inner_tag>::read_from($reader)?;
+        let mut tag: $tag = inner_tag.into();
+        tag.set_title(&($info).name);
+        tag.set_artist(&construct_artist_list(&($info).artist));
+        tag.set_album_title(&($info).album);
+        if let Some(cover) = $cover {
+            tag.set_album_cover(cover);
+        }
+        let mut inner_tag: $inner_tag = tag.into();
+        inner_tag.write_with_tag_to($writer)?;
+
+        Ok(())
+    }};
+}
 
 pub trait TagWrite {
     fn write_with_tag_to(&mut self, writer: &mut impl std::io::Write) -> DumpResult<()>;
@@ -19,3 +46,87 @@ impl TagWrite for FlacInnerTag {
             .map_err(|e| Error::TagWritedError(e.to_string()))
     }
 }
+
+/// An ID3v2 tag for containers that carry one in a chunk of their own.
+pub(crate) fn id3_tag(info: &NcmInfo, cover: Option<&Picture>) -> ID3v2InnerTag {
+    let mut tag = Id3v2Tag::from(ID3v2InnerTag::new());
+    tag.set_title(&info.name);
+    tag.set_artist(&construct_artist_list(&info.artist));
+    tag.set_album_title(&info.album);
+    if let Some(cover) = cover {
+        tag.set_album_cover(cover.clone());
+    }
+    tag.into()
+}
+
+fn construct_artist_list(artists: &[(String, u64)]) -> String {
+    let artists: Vec<&str> = artists.iter().map(|(s, _)| s.as_str()).collect();
+    artists.join(",")
+}
+
+/// Tag the audio stream read from `reader`, a `media_format` container, with
+/// `info` and an optional cover, then write it out. `reader` must be at the
+/// start of the audio.
+pub(crate) fn write_with_info(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + Seek),
+    info: &NcmInfo,
+    media_format: MediaFormat,
+    image: Option<&[u8]>,
+) -> DumpResult<()> {
+    let cover = match image {
+        Some(image) => {
+            let image_format = image::guess_format(image).map_err(|_| Error::ImageFormatError)?;
+            Some(Picture::new(
+                image,
+                image_to_audiotag_mimetype(image_format)?,
+            ))
+        }
+        None => None,
+    };
+
+    let tag_reader = &mut &mut *reader;
+    match media_format {
+        MediaFormat::ID3v2 => {
+            let res: DumpResult<()> =
+                write_tag!(Id3v2Tag, ID3v2InnerTag, tag_reader, writer, info, cover);
+            std::io::copy(reader, writer)?;
+            res
+        }
+        MediaFormat::fLaC => {
+            let res: DumpResult<()> =
+                write_tag!(FlacTag, FlacInnerTag, tag_reader, writer, info, cover);
+            std::io::copy(reader, writer)?;
+            res
+        }
+        MediaFormat::Ogg | MediaFormat::Opus => {
+            ogg::write_tagged(reader, writer, info, cover.as_ref())
+        }
+        MediaFormat::M4A => mp4::write_tagged(reader, writer, info, cover.as_ref()),
+        MediaFormat::WAV => riff::write_tagged(reader, writer, info, cover.as_ref()),
+        MediaFormat::APE | MediaFormat::WavPack => {
+            ape::write_tagged(reader, writer, info, cover.as_ref())
+        }
+        MediaFormat::DSF => dsf::write_tagged(reader, writer, info, cover.as_ref()),
+        // ADTS streams take an ID3v2 tag in front
+        MediaFormat::AAC => {
+            id3_tag(info, cover.as_ref()).write_with_tag_to(writer)?;
+            std::io::copy(reader, writer)?;
+            Ok(())
+        }
+        _ => Err(Error::TagBuildError("Unsupported format".to_string())),
+    }?;
+
+    Ok(())
+}
+
+fn image_to_audiotag_mimetype(image_mime: image::ImageFormat) -> DumpResult<audiotags::MimeType> {
+    match image_mime {
+        image::ImageFormat::Png => Ok(audiotags::MimeType::Png),
+        image::ImageFormat::Jpeg => Ok(audiotags::MimeType::Jpeg),
+        image::ImageFormat::Gif => Ok(audiotags::MimeType::Gif),
+        image::ImageFormat::Tiff => Ok(audiotags::MimeType::Tiff),
+        image::ImageFormat::Bmp => Ok(audiotags::MimeType::Bmp),
+        _ => Err(Error::ImageUnsupportedError),
+    }
+}
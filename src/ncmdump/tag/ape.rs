@@ -0,0 +1,185 @@
+//! APEv2 tags at the end of Monkey's Audio and WavPack files.
+
+use super::super::error::{DumpResult, Error};
+use super::super::NcmInfo;
+use super::construct_artist_list;
+use audiotags::{MimeType, Picture};
+use std::io::{Read, Write};
+
+const PREAMBLE: &[u8; 8] = b"APETAGEX";
+const VERSION: u32 = 2000;
+const HEADER_LEN: usize = 32;
+const ID3V1_LEN: usize = 128;
+
+const FLAG_HAS_HEADER: u32 = 1 << 31;
+const FLAG_IS_HEADER: u32 = 1 << 29;
+const FLAG_BINARY: u32 = 1 << 1;
+
+const COVER_KEY: &str = "Cover Art (Front)";
+const REPLACED_KEYS: [&str; 4] = ["Title", "Artist", "Album", COVER_KEY];
+
+struct Item {
+    key: String,
+    flags: u32,
+    value: Vec<u8>,
+}
+
+fn broken() -> Error {
+    Error::TagBuildError("Broken APEv2 tag".to_string())
+}
+
+/// Split a file into its audio and the items of the APEv2 tag at its end.
+/// An ID3v1 tag after it is dropped.
+fn split_tag(mut file: &[u8]) -> DumpResult<(&[u8], Vec<Item>)> {
+    if file.len() >= ID3V1_LEN && file[file.len() - ID3V1_LEN..].starts_with(b"TAG") {
+        file = &file[..file.len() - ID3V1_LEN];
+    }
+    if file.len() < HEADER_LEN || !file[file.len() - HEADER_LEN..].starts_with(PREAMBLE) {
+        return Ok((file, vec![]));
+    }
+
+    let footer = &file[file.len() - HEADER_LEN..];
+    let field = |at: usize| u32::from_le_bytes(footer[at..at + 4].try_into().unwrap());
+    let (size, count, flags) = (field(12) as usize, field(16), field(20));
+    let header_len = if flags & FLAG_HAS_HEADER != 0 {
+        HEADER_LEN
+    } else {
+        0
+    };
+    let tag_len = size + header_len;
+    if size < HEADER_LEN || tag_len > file.len() {
+        return Err(broken());
+    }
+
+    let mut items = vec![];
+    let mut rest = &file[file.len() - size..file.len() - HEADER_LEN];
+    for _ in 0..count {
+        if rest.len() < 8 {
+            return Err(broken());
+        }
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let flags = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+        let key_len = rest[8..].iter().position(|&b| b == 0).ok_or_else(broken)?;
+        let key = String::from_utf8_lossy(&rest[8..8 + key_len]).into_owned();
+        let value = rest
+            .get(9 + key_len..9 + key_len + len)
+            .ok_or_else(broken)?;
+        items.push(Item {
+            key,
+            flags,
+            value: value.to_vec(),
+        });
+        rest = &rest[9 + key_len + len..];
+    }
+    Ok((&file[..file.len() - tag_len], items))
+}
+
+fn header(size: usize, count: usize, flags: u32) -> Vec<u8> {
+    let mut header = PREAMBLE.to_vec();
+    header.extend(VERSION.to_le_bytes());
+    header.extend((size as u32).to_le_bytes());
+    header.extend((count as u32).to_le_bytes());
+    header.extend(flags.to_le_bytes());
+    header.extend([0u8; 8]);
+    header
+}
+
+/// Read the whole file, then write it again with a fresh APEv2 tag.
+pub(crate) fn write_tagged(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut impl Write,
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<()> {
+    let mut file = vec![];
+    reader.read_to_end(&mut file)?;
+    let (audio, old_items) = split_tag(&file)?;
+
+    let mut items: Vec<Item> = old_items
+        .into_iter()
+        .filter(|item| {
+            !REPLACED_KEYS
+                .iter()
+                .any(|k| k.eq_ignore_ascii_case(&item.key))
+        })
+        .collect();
+    let artist = construct_artist_list(&info.artist);
+    for (key, value) in [
+        ("Title", &info.name),
+        ("Artist", &artist),
+        ("Album", &info.album),
+    ] {
+        items.push(Item {
+            key: key.to_string(),
+            flags: 0,
+            value: value.as_bytes().to_vec(),
+        });
+    }
+    if let Some(cover) = cover {
+        let name: &[u8] = match cover.mime_type {
+            MimeType::Png => b"cover.png\0",
+            MimeType::Bmp => b"cover.bmp\0",
+            MimeType::Gif => b"cover.gif\0",
+            MimeType::Tiff => b"cover.tiff\0",
+            MimeType::Jpeg => b"cover.jpg\0",
+        };
+        items.push(Item {
+            key: COVER_KEY.to_string(),
+            flags: FLAG_BINARY,
+            value: [name, cover.data].concat(),
+        });
+    }
+
+    let mut body = vec![];
+    for item in &items {
+        body.extend((item.value.len() as u32).to_le_bytes());
+        body.extend(item.flags.to_le_bytes());
+        body.extend(item.key.as_bytes());
+        body.push(0);
+        body.extend(&item.value);
+    }
+    let size = body.len() + HEADER_LEN;
+
+    writer.write_all(audio)?;
+    writer.write_all(&header(size, items.len(), FLAG_HAS_HEADER | FLAG_IS_HEADER))?;
+    writer.write_all(&body)?;
+    writer.write_all(&header(size, items.len(), FLAG_HAS_HEADER))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_tag, write_tagged};
+    use crate::NcmInfo;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_tagged() {
+        let info = NcmInfo {
+            name: "Old".to_string(),
+            ..Default::default()
+        };
+        let mut tagged = vec![];
+        write_tagged(&mut Cursor::new(b"MAC audio"), &mut tagged, &info, None).unwrap();
+
+        // Tag it again, over the old tag and an ID3v1 tag after it
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+        tagged.extend(id3v1);
+        let info = NcmInfo {
+            name: "Title".to_string(),
+            artist: vec![("A".to_string(), 1)],
+            ..Default::default()
+        };
+        let mut output = vec![];
+        write_tagged(&mut Cursor::new(tagged), &mut output, &info, None).unwrap();
+
+        assert!(output.starts_with(b"MAC audio"));
+        let (audio, items) = split_tag(&output).unwrap();
+        assert_eq!(audio, b"MAC audio");
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].key, "Title");
+        assert_eq!(items[0].value, b"Title");
+        assert_eq!(items[1].value, b"A");
+    }
+}
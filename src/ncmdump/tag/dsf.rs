@@ -0,0 +1,85 @@
+//! ID3v2 tags of DSF files, kept at the end and pointed to by the `DSD ` chunk.
+
+use super::super::error::{DumpResult, Error};
+use super::super::NcmInfo;
+use super::id3_tag;
+use audiotags::Picture;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const DSD_CHUNK_LEN: u64 = 28;
+
+pub(crate) fn write_tagged(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + Seek),
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<()> {
+    let mut header = [0u8; DSD_CHUNK_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"DSD " {
+        return Err(Error::TagBuildError("Broken DSF file".to_string()));
+    }
+    let total_len = u64::from_le_bytes(header[12..20].try_into().unwrap());
+    let metadata_pointer = u64::from_le_bytes(header[20..28].try_into().unwrap());
+
+    // The `fmt ` and `data` chunks, without the old tag
+    let audio_end = match metadata_pointer {
+        0 => total_len,
+        p => p,
+    };
+    let start = writer.stream_position()?;
+    writer.write_all(&header)?;
+    let audio_len = std::io::copy(
+        &mut Read::take(reader, audio_end.saturating_sub(DSD_CHUNK_LEN)),
+        writer,
+    )?;
+
+    let mut tag = vec![];
+    id3_tag(info, cover)
+        .write_to(&mut tag, id3::Version::Id3v23)
+        .map_err(|e| Error::TagWritedError(e.to_string()))?;
+    writer.write_all(&tag)?;
+
+    let metadata_pointer = DSD_CHUNK_LEN + audio_len;
+    let total_len = metadata_pointer + tag.len() as u64;
+    writer.seek(SeekFrom::Start(start + 12))?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(&metadata_pointer.to_le_bytes())?;
+    writer.seek(SeekFrom::Start(start + total_len))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_tagged, DSD_CHUNK_LEN};
+    use crate::NcmInfo;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_tagged() {
+        let audio = b"fmt and data chunks";
+        let old_tag = b"ID3 old tag";
+        let total_len = DSD_CHUNK_LEN + (audio.len() + old_tag.len()) as u64;
+        let mut file = b"DSD ".to_vec();
+        file.extend(DSD_CHUNK_LEN.to_le_bytes());
+        file.extend(total_len.to_le_bytes());
+        file.extend((DSD_CHUNK_LEN + audio.len() as u64).to_le_bytes());
+        file.extend(audio);
+        file.extend(old_tag);
+
+        let info = NcmInfo {
+            name: "Title".to_string(),
+            ..Default::default()
+        };
+        let mut output = Cursor::new(vec![]);
+        write_tagged(&mut Cursor::new(file), &mut output, &info, None).unwrap();
+        let output = output.into_inner();
+
+        let total_len = u64::from_le_bytes(output[12..20].try_into().unwrap());
+        let pointer = u64::from_le_bytes(output[20..28].try_into().unwrap()) as usize;
+        assert_eq!(total_len as usize, output.len());
+        assert_eq!(&output[DSD_CHUNK_LEN as usize..pointer], audio);
+        let tag = id3::Tag::read_from2(Cursor::new(&output[pointer..])).unwrap();
+        assert_eq!(id3::TagLike::title(&tag), Some("Title"));
+    }
+}
@@ -0,0 +1,283 @@
+//! iTunes-style `ilst` metadata of MP4/M4A files.
+//!
+//! The whole file is loaded, since `moov` may come before the audio and its
+//! chunk offsets have to move along when it grows.
+
+use super::super::error::{DumpResult, Error};
+use super::super::NcmInfo;
+use super::construct_artist_list;
+use audiotags::{MimeType, Picture};
+use std::io::{Read, Write};
+
+const TITLE: [u8; 4] = *b"\xA9nam";
+const ARTIST: [u8; 4] = *b"\xA9ART";
+const ALBUM: [u8; 4] = *b"\xA9alb";
+const COVER: [u8; 4] = *b"covr";
+
+/// Boxes on the way from `moov` to the chunk offset tables.
+const SAMPLE_TABLE_PATH: [&[u8; 4]; 4] = [b"trak", b"mdia", b"minf", b"stbl"];
+
+fn broken() -> Error {
+    Error::TagBuildError("Broken MP4 file".to_string())
+}
+
+struct Mp4Box<'a> {
+    kind: [u8; 4],
+    /// The whole box, header included
+    raw: &'a [u8],
+    header_len: usize,
+}
+
+impl<'a> Mp4Box<'a> {
+    fn body(&self) -> &'a [u8] {
+        &self.raw[self.header_len..]
+    }
+}
+
+fn parse_boxes(mut data: &[u8]) -> DumpResult<Vec<Mp4Box<'_>>> {
+    let mut boxes = vec![];
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err(broken());
+        }
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as u64;
+        let kind: [u8; 4] = data[4..8].try_into().unwrap();
+        let (size, header_len) = match size {
+            0 => (data.len() as u64, 8),
+            1 => {
+                let large = data.get(8..16).ok_or_else(broken)?;
+                (u64::from_be_bytes(large.try_into().unwrap()), 16)
+            }
+            size => (size, 8),
+        };
+        if size < header_len as u64 || size > data.len() as u64 {
+            return Err(broken());
+        }
+        let (raw, rest) = data.split_at(size as usize);
+        boxes.push(Mp4Box {
+            kind,
+            raw,
+            header_len,
+        });
+        data = rest;
+    }
+    Ok(boxes)
+}
+
+fn make_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(body.len() + 8);
+    res.extend((body.len() as u32 + 8).to_be_bytes());
+    res.extend(kind);
+    res.extend(body);
+    res
+}
+
+/// An `ilst` item holding a single `data` box.
+fn make_item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    data.extend(data_type.to_be_bytes());
+    // Locale
+    data.extend([0u8; 4]);
+    data.extend(value);
+    make_box(kind, &make_box(b"data", &data))
+}
+
+fn build_ilst(old: Option<&[u8]>, info: &NcmInfo, cover: Option<&Picture>) -> DumpResult<Vec<u8>> {
+    let mut body = vec![];
+    if let Some(old) = old {
+        for item in parse_boxes(old)? {
+            if ![TITLE, ARTIST, ALBUM, COVER].contains(&item.kind) {
+                body.extend(item.raw);
+            }
+        }
+    }
+
+    // UTF-8 text
+    body.extend(make_item(&TITLE, 1, info.name.as_bytes()));
+    let artist = construct_artist_list(&info.artist);
+    body.extend(make_item(&ARTIST, 1, artist.as_bytes()));
+    body.extend(make_item(&ALBUM, 1, info.album.as_bytes()));
+    if let Some(cover) = cover {
+        let data_type = match cover.mime_type {
+            MimeType::Png => 14,
+            MimeType::Bmp => 27,
+            _ => 13,
+        };
+        body.extend(make_item(&COVER, data_type, cover.data));
+    }
+    Ok(make_box(b"ilst", &body))
+}
+
+/// Rebuild `udta` with a fresh `meta` box, keeping everything else in it.
+fn build_udta(
+    old: Option<&Mp4Box>,
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<Vec<u8>> {
+    let mut body = vec![];
+    let mut old_ilst = None;
+    if let Some(old) = old {
+        for child in parse_boxes(old.body())? {
+            if &child.kind != b"meta" {
+                body.extend(child.raw);
+                continue;
+            }
+            // `meta` is a full box, with 4 bytes of version and flags
+            let meta_children = child.body().get(4..).ok_or_else(broken)?;
+            old_ilst = parse_boxes(meta_children)?
+                .into_iter()
+                .find(|b| &b.kind == b"ilst")
+                .map(|b| b.body());
+        }
+    }
+
+    let mut hdlr = vec![0u8; 8];
+    hdlr.extend(b"mdirappl");
+    hdlr.extend([0u8; 9]);
+    let mut meta = vec![0u8; 4];
+    meta.extend(make_box(b"hdlr", &hdlr));
+    meta.extend(build_ilst(old_ilst, info, cover)?);
+
+    body.extend(make_box(b"meta", &meta));
+    Ok(make_box(b"udta", &body))
+}
+
+/// Move every chunk offset in the sample tables under `moov` by `shift`.
+fn shift_chunk_offsets(moov: &mut [u8], depth: usize, shift: i64) -> DumpResult<()> {
+    let mut pos = 0;
+    while pos < moov.len() {
+        let boxes = parse_boxes(&moov[pos..])?;
+        let Some(child) = boxes.first() else {
+            break;
+        };
+        let (kind, size, header_len) = (child.kind, child.raw.len(), child.header_len);
+        let body = &mut moov[pos + header_len..pos + size];
+
+        match &kind {
+            kind if depth < SAMPLE_TABLE_PATH.len() && kind == SAMPLE_TABLE_PATH[depth] => {
+                shift_chunk_offsets(body, depth + 1, shift)?;
+            }
+            b"stco" | b"co64" if depth == SAMPLE_TABLE_PATH.len() => {
+                let width = if &kind == b"stco" { 4 } else { 8 };
+                let count = body.get(4..8).ok_or_else(broken)?;
+                let count = u32::from_be_bytes(count.try_into().unwrap()) as usize;
+                let entries = body.get_mut(8..8 + count * width).ok_or_else(broken)?;
+                for entry in entries.chunks_exact_mut(width) {
+                    if width == 4 {
+                        let offset = u32::from_be_bytes(entry.try_into().unwrap()) as i64;
+                        let offset = u32::try_from(offset + shift).map_err(|_| broken())?;
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    } else {
+                        let offset = u64::from_be_bytes(entry.try_into().unwrap()) as i64;
+                        entry.copy_from_slice(&((offset + shift) as u64).to_be_bytes());
+                    }
+                }
+            }
+            _ => (),
+        }
+        pos += size;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_tagged(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut impl Write,
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<()> {
+    let mut file = vec![];
+    reader.read_to_end(&mut file)?;
+    let boxes = parse_boxes(&file)?;
+
+    let moov_index = boxes
+        .iter()
+        .position(|b| &b.kind == b"moov")
+        .ok_or_else(broken)?;
+    let moov = &boxes[moov_index];
+    let children = parse_boxes(moov.body())?;
+
+    let mut body = vec![];
+    let mut udta = None;
+    for child in &children {
+        if &child.kind == b"udta" {
+            udta = Some(child);
+        } else {
+            body.extend(child.raw);
+        }
+    }
+    body.extend(build_udta(udta, info, cover)?);
+
+    // The audio moves only if it lies after `moov`
+    let shift = body.len() as i64 + 8 - moov.raw.len() as i64;
+    let audio_after = boxes[moov_index + 1..].iter().any(|b| &b.kind == b"mdat");
+    if audio_after && shift != 0 {
+        shift_chunk_offsets(&mut body, 0, shift)?;
+    }
+
+    for (i, b) in boxes.iter().enumerate() {
+        if i == moov_index {
+            writer.write_all(&make_box(b"moov", &body))?;
+        } else {
+            writer.write_all(b.raw)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{make_box, parse_boxes, write_tagged};
+    use crate::NcmInfo;
+    use audiotags::{MimeType, Picture};
+    use std::io::Cursor;
+
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        let boxes = parse_boxes(data).unwrap();
+        let found = boxes.iter().find(|b| &b.kind == path[0]).unwrap();
+        match path.len() {
+            1 => found.body(),
+            _ if path[0] == b"meta" => find(&found.body()[4..], &path[1..]),
+            _ => find(found.body(), &path[1..]),
+        }
+    }
+
+    #[test]
+    fn test_write_tagged() {
+        let mut stco = vec![0u8; 4];
+        stco.extend(1u32.to_be_bytes());
+        let stbl = make_box(b"stbl", &make_box(b"stco", &[stco, vec![0u8; 4]].concat()));
+        let trak = make_box(b"trak", &make_box(b"mdia", &make_box(b"minf", &stbl)));
+        let moov = make_box(b"moov", &trak);
+        let ftyp = make_box(b"ftyp", b"M4A \0\0\0\0");
+
+        // Point the only chunk at the start of the audio
+        let audio_start = (ftyp.len() + moov.len() + 8) as u32;
+        let mut file = [ftyp, moov, make_box(b"mdat", b"audio")].concat();
+        let stco_entry = file.len() - 4 - 8 - 5;
+        file[stco_entry..stco_entry + 4].copy_from_slice(&audio_start.to_be_bytes());
+
+        let info = NcmInfo {
+            name: "Title".to_string(),
+            artist: vec![("A".to_string(), 1)],
+            album: "Album".to_string(),
+            ..Default::default()
+        };
+        let cover = Picture::new(b"\x89PNG", MimeType::Png);
+        let mut output = vec![];
+        write_tagged(&mut Cursor::new(file), &mut output, &info, Some(&cover)).unwrap();
+
+        let ilst = find(&output, &[b"moov", b"udta", b"meta", b"ilst"]);
+        let title = find(ilst, &[b"\xA9nam", b"data"]);
+        assert_eq!(&title[8..], b"Title");
+        let covr = find(ilst, &[b"covr", b"data"]);
+        assert_eq!(&covr[..4], &14u32.to_be_bytes());
+
+        let stco = find(
+            &output,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stco"],
+        );
+        let offset = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+        assert_eq!(&output[offset..offset + 5], b"audio");
+    }
+}
@@ -0,0 +1,434 @@
+//! Vorbis comments of Ogg Vorbis and Ogg Opus streams.
+//!
+//! The comment packet is the second packet of the stream. It is rebuilt and
+//! paginated again together with the other header packets. Audio pages are
+//! copied as they are, unless the number of header pages changed and their
+//! sequence numbers have to follow.
+
+use super::super::error::{DumpResult, Error};
+use super::super::NcmInfo;
+use super::construct_artist_list;
+use audiotags::Picture;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{Read, Write};
+
+const CAPTURE: &[u8; 4] = b"OggS";
+const PAGE_HEADER_LEN: usize = 27;
+const FLAG_CONTINUED: u8 = 0x01;
+
+const REPLACED_FIELDS: [&str; 4] = ["TITLE", "ARTIST", "ALBUM", "METADATA_BLOCK_PICTURE"];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Vorbis,
+    Opus,
+}
+
+impl Codec {
+    fn from_id_header(packet: &[u8]) -> Option<Self> {
+        match packet {
+            [0x01, b'v', b'o', b'r', b'b', b'i', b's', ..] => Some(Self::Vorbis),
+            [b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', ..] => Some(Self::Opus),
+            _ => None,
+        }
+    }
+
+    fn comment_magic(&self) -> &'static [u8] {
+        match self {
+            Self::Vorbis => b"\x03vorbis",
+            Self::Opus => b"OpusTags",
+        }
+    }
+
+    /// Header packets following the identification header.
+    fn extra_headers(&self) -> usize {
+        match self {
+            Self::Vorbis => 2,
+            Self::Opus => 1,
+        }
+    }
+}
+
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Page {
+    fn read_from(reader: &mut (impl Read + ?Sized)) -> DumpResult<Option<Self>> {
+        let mut header = [0u8; PAGE_HEADER_LEN];
+        let size = reader.take(PAGE_HEADER_LEN as u64).read(&mut header)?;
+        if size == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut header[size..])?;
+        if &header[..4] != CAPTURE {
+            return Err(Error::TagBuildError("Broken Ogg page".to_string()));
+        }
+
+        let mut lacing = vec![0u8; header[26] as usize];
+        reader.read_exact(&mut lacing)?;
+        let mut body = vec![0u8; lacing.iter().map(|&l| l as usize).sum()];
+        reader.read_exact(&mut body)?;
+
+        Ok(Some(Self {
+            header_type: header[5],
+            granule: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+            serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+            sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+            lacing,
+            body,
+        }))
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> DumpResult<()> {
+        let mut page = Vec::with_capacity(PAGE_HEADER_LEN + self.lacing.len() + self.body.len());
+        page.extend(CAPTURE);
+        page.push(0);
+        page.push(self.header_type);
+        page.extend(self.granule.to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        page.extend([0u8; 4]);
+        page.push(self.lacing.len() as u8);
+        page.extend(&self.lacing);
+        page.extend(&self.body);
+
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&page)?;
+        Ok(())
+    }
+
+    /// Split the body into packet pieces, each with whether it ends a packet.
+    fn pieces(&self) -> Vec<(&[u8], bool)> {
+        let mut pieces = vec![];
+        let (mut start, mut len) = (0, 0);
+        for &l in &self.lacing {
+            len += l as usize;
+            if l < 255 {
+                pieces.push((&self.body[start..start + len], true));
+                start += len;
+                len = 0;
+            }
+        }
+        if len > 0 || start < self.body.len() {
+            pieces.push((&self.body[start..], false));
+        }
+        pieces
+    }
+}
+
+/// Put header packets on as few pages as possible, starting at `sequence`.
+fn paginate(packets: &[Vec<u8>], serial: u32, sequence: u32) -> Vec<Page> {
+    let mut pages = vec![];
+    let mut page = Page {
+        header_type: 0,
+        granule: 0,
+        serial,
+        sequence,
+        lacing: vec![],
+        body: vec![],
+    };
+
+    for packet in packets {
+        let mut rest = &packet[..];
+        let mut continued = false;
+        loop {
+            if page.lacing.len() == 255 {
+                let next = Page {
+                    header_type: if continued { FLAG_CONTINUED } else { 0 },
+                    granule: 0,
+                    serial,
+                    sequence: page.sequence + 1,
+                    lacing: vec![],
+                    body: vec![],
+                };
+                pages.push(std::mem::replace(&mut page, next));
+            }
+            let len = rest.len().min(255);
+            page.lacing.push(len as u8);
+            page.body.extend(&rest[..len]);
+            rest = &rest[len..];
+            // A packet of a multiple of 255 bytes ends with a 0 lacing value
+            if len < 255 {
+                break;
+            }
+            continued = true;
+        }
+    }
+    pages.push(page);
+
+    // Pages on which no packet ends carry a granule position of -1
+    for page in pages.iter_mut() {
+        if page.lacing.iter().all(|&l| l == 255) {
+            page.granule = u64::MAX;
+        }
+    }
+    pages
+}
+
+fn comment_packet(
+    codec: Codec,
+    old: &[u8],
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<Vec<u8>> {
+    let broken = || Error::TagBuildError("Broken Vorbis comment".to_string());
+    if !old.starts_with(codec.comment_magic()) {
+        return Err(broken());
+    }
+    let mut pos = codec.comment_magic().len();
+    let read_u32 = |pos: &mut usize| -> DumpResult<u32> {
+        let bytes = old.get(*pos..*pos + 4).ok_or_else(broken)?;
+        *pos += 4;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let read_bytes = |pos: &mut usize, len: usize| -> DumpResult<&[u8]> {
+        let bytes = old.get(*pos..*pos + len).ok_or_else(broken)?;
+        *pos += len;
+        Ok(bytes)
+    };
+
+    let vendor_len = read_u32(&mut pos)? as usize;
+    let vendor = read_bytes(&mut pos, vendor_len)?;
+    let count = read_u32(&mut pos)?;
+    let mut comments = vec![];
+    for _ in 0..count {
+        let len = read_u32(&mut pos)? as usize;
+        let comment = read_bytes(&mut pos, len)?;
+        let key = comment.split(|&b| b == b'=').next().unwrap_or_default();
+        let replaced = REPLACED_FIELDS
+            .iter()
+            .any(|f| f.as_bytes().eq_ignore_ascii_case(key));
+        if !replaced {
+            comments.push(comment.to_vec());
+        }
+    }
+
+    comments.push(format!("TITLE={}", info.name).into_bytes());
+    comments.push(format!("ARTIST={}", construct_artist_list(&info.artist)).into_bytes());
+    comments.push(format!("ALBUM={}", info.album).into_bytes());
+    if let Some(cover) = cover {
+        let block = picture_block(cover);
+        comments.push(format!("METADATA_BLOCK_PICTURE={}", STANDARD.encode(block)).into_bytes());
+    }
+
+    let mut packet = codec.comment_magic().to_vec();
+    packet.extend((vendor.len() as u32).to_le_bytes());
+    packet.extend(vendor);
+    packet.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        packet.extend((comment.len() as u32).to_le_bytes());
+        packet.extend(comment);
+    }
+    if codec == Codec::Vorbis {
+        // Framing bit
+        packet.push(1);
+    }
+    Ok(packet)
+}
+
+/// A FLAC picture block, the way Vorbis comments carry a cover.
+fn picture_block(cover: &Picture) -> Vec<u8> {
+    let mime: &str = cover.mime_type.into();
+    let mut block = vec![];
+    // Front cover
+    block.extend(3u32.to_be_bytes());
+    block.extend((mime.len() as u32).to_be_bytes());
+    block.extend(mime.as_bytes());
+    // Description, width, height, depth and colors are left empty
+    block.extend([0u8; 20]);
+    block.extend((cover.data.len() as u32).to_be_bytes());
+    block.extend(cover.data);
+    block
+}
+
+pub(crate) fn write_tagged(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut impl Write,
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<()> {
+    let unsupported = || Error::TagBuildError("Unsupported Ogg stream".to_string());
+
+    let first = Page::read_from(reader)?.ok_or_else(unsupported)?;
+    let codec = Codec::from_id_header(&first.body).ok_or_else(unsupported)?;
+    let serial = first.serial;
+
+    // Gather the header packets after the identification header. They end
+    // on a page boundary, and the audio starts on a fresh page.
+    let mut packets: Vec<Vec<u8>> = vec![];
+    let mut packet = vec![];
+    let mut old_pages = 0;
+    while packets.len() < codec.extra_headers() {
+        let page = Page::read_from(reader)?.ok_or_else(unsupported)?;
+        if page.serial != serial {
+            return Err(unsupported());
+        }
+        old_pages += 1;
+        for (piece, complete) in page.pieces() {
+            packet.extend(piece);
+            if complete {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+    if packets.len() != codec.extra_headers() || !packet.is_empty() {
+        return Err(unsupported());
+    }
+
+    packets[0] = comment_packet(codec, &packets[0], info, cover)?;
+    let pages = paginate(&packets, serial, first.sequence + 1);
+    let shift = pages.len() as i64 - old_pages;
+
+    first.write_to(writer)?;
+    for page in &pages {
+        page.write_to(writer)?;
+    }
+
+    if shift == 0 {
+        std::io::copy(reader, writer)?;
+        return Ok(());
+    }
+    while let Some(mut page) = Page::read_from(reader)? {
+        if page.serial == serial {
+            page.sequence = (page.sequence as i64 + shift) as u32;
+        }
+        page.write_to(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, paginate, write_tagged, Page};
+    use crate::NcmInfo;
+    use audiotags::{MimeType, Picture};
+    use std::io::Cursor;
+
+    fn stream(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = vec![];
+        let id = Page {
+            header_type: 0x02,
+            granule: 0,
+            serial: 7,
+            sequence: 0,
+            lacing: vec![packets[0].len() as u8],
+            body: packets[0].clone(),
+        };
+        id.write_to(&mut file).unwrap();
+        for page in paginate(&packets[1..], 7, 1) {
+            page.write_to(&mut file).unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn test_crc() {
+        // CRC-32/POSIX before its final inversion
+        assert_eq!(crc32(b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn test_write_tagged() {
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend(4u32.to_le_bytes());
+        comment.extend(b"test");
+        comment.extend(2u32.to_le_bytes());
+        for c in [&b"TITLE=old"[..], b"GENRE=pop"] {
+            comment.extend((c.len() as u32).to_le_bytes());
+            comment.extend(c);
+        }
+        comment.push(1);
+        let setup = {
+            let mut setup = b"\x05vorbis".to_vec();
+            setup.resize(600, 0xAB);
+            setup
+        };
+        let mut id = b"\x01vorbis".to_vec();
+        id.resize(30, 0);
+        let mut file = stream(&[id, comment, setup.clone()]);
+
+        let audio = Page {
+            header_type: 0,
+            granule: 1024,
+            serial: 7,
+            sequence: 2,
+            lacing: vec![10],
+            body: vec![0x55; 10],
+        };
+        audio.write_to(&mut file).unwrap();
+
+        let info = NcmInfo {
+            name: "Title".to_string(),
+            artist: vec![("A".to_string(), 1), ("B".to_string(), 2)],
+            album: "Album".to_string(),
+            ..Default::default()
+        };
+        // A large cover spreads the comment over more pages
+        let image = vec![0xEE; 100_000];
+        let cover = Picture::new(&image, MimeType::Jpeg);
+        for cover in [None, Some(&cover)] {
+            let mut output = vec![];
+            write_tagged(&mut Cursor::new(&file), &mut output, &info, cover).unwrap();
+
+            let mut output = Cursor::new(output);
+            let mut pages = vec![];
+            while let Some(page) = Page::read_from(&mut output).unwrap() {
+                pages.push(page);
+            }
+            let mut rewritten = vec![];
+            for (i, page) in pages.iter().enumerate() {
+                assert_eq!(page.sequence, i as u32);
+                page.write_to(&mut rewritten).unwrap();
+            }
+            assert_eq!(rewritten, output.into_inner());
+            assert_eq!(pages.len() > 3, cover.is_some());
+
+            let headers: Vec<u8> = pages[1..pages.len() - 1]
+                .iter()
+                .flat_map(|p| p.body.clone())
+                .collect();
+            let text = String::from_utf8_lossy(&headers);
+            assert!(text.contains("GENRE=pop"));
+            assert!(text.contains("TITLE=Title"));
+            assert!(text.contains("ARTIST=A,B"));
+            assert!(!text.contains("TITLE=old"));
+            assert_eq!(text.contains("METADATA_BLOCK_PICTURE="), cover.is_some());
+            assert!(headers.ends_with(&setup));
+            assert_eq!(pages.last().unwrap().body, vec![0x55; 10]);
+        }
+    }
+}
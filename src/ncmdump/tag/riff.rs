@@ -0,0 +1,144 @@
+//! `LIST`/`INFO` and `id3 ` chunks of RIFF WAVE files.
+
+use super::super::error::{DumpResult, Error};
+use super::super::NcmInfo;
+use super::construct_artist_list;
+use super::id3_tag;
+use audiotags::Picture;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+fn is_tag_chunk(id: &[u8; 4], body_start: &[u8]) -> bool {
+    match id {
+        b"LIST" => body_start == b"INFO",
+        b"id3 " | b"ID3 " => true,
+        _ => false,
+    }
+}
+
+fn write_chunk(writer: &mut impl Write, id: &[u8; 4], body: &[u8]) -> DumpResult<u64> {
+    writer.write_all(id)?;
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(body)?;
+    let mut len = 8 + body.len() as u64;
+    if body.len() % 2 == 1 {
+        writer.write_all(&[0])?;
+        len += 1;
+    }
+    Ok(len)
+}
+
+fn info_list(info: &NcmInfo) -> Vec<u8> {
+    let mut list = b"INFO".to_vec();
+    let artist = construct_artist_list(&info.artist);
+    for (id, text) in [
+        (b"INAM", &info.name),
+        (b"IART", &artist),
+        (b"IPRD", &info.album),
+    ] {
+        let mut text = text.as_bytes().to_vec();
+        text.push(0);
+        let len = text.len() as u32;
+        list.extend(id);
+        list.extend(len.to_le_bytes());
+        list.extend(text);
+        if len % 2 == 1 {
+            list.push(0);
+        }
+    }
+    list
+}
+
+/// Copy every chunk except old tags, then append fresh ones and fix the size
+/// of the `RIFF` chunk.
+pub(crate) fn write_tagged(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + Seek),
+    info: &NcmInfo,
+    cover: Option<&Picture>,
+) -> DumpResult<()> {
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+        return Err(Error::TagBuildError("Broken WAV file".to_string()));
+    }
+    let start = writer.stream_position()?;
+    writer.write_all(&header)?;
+    let mut len = 4u64;
+
+    loop {
+        let mut chunk_header = [0u8; 8];
+        let size = Read::take(&mut *reader, 8).read(&mut chunk_header)?;
+        if size == 0 {
+            break;
+        }
+        reader.read_exact(&mut chunk_header[size..])?;
+        let id: [u8; 4] = chunk_header[..4].try_into().unwrap();
+        let chunk_len = u32::from_le_bytes(chunk_header[4..].try_into().unwrap()) as u64;
+        let padded_len = chunk_len + chunk_len % 2;
+
+        let mut body_start = vec![];
+        Read::take(&mut *reader, 4.min(padded_len)).read_to_end(&mut body_start)?;
+        let rest = padded_len - body_start.len() as u64;
+        if is_tag_chunk(&id, &body_start) {
+            std::io::copy(&mut Read::take(&mut *reader, rest), &mut std::io::sink())?;
+            continue;
+        }
+
+        writer.write_all(&chunk_header)?;
+        writer.write_all(&body_start)?;
+        let copied = std::io::copy(&mut Read::take(&mut *reader, rest), writer)?;
+        len += 8 + body_start.len() as u64 + copied;
+        if copied < rest {
+            // A truncated last chunk
+            break;
+        }
+    }
+
+    len += write_chunk(writer, b"LIST", &info_list(info))?;
+    let mut id3 = vec![];
+    id3_tag(info, cover)
+        .write_to(&mut id3, id3::Version::Id3v23)
+        .map_err(|e| Error::TagWritedError(e.to_string()))?;
+    len += write_chunk(writer, b"id3 ", &id3)?;
+
+    writer.seek(SeekFrom::Start(start + 4))?;
+    writer.write_all(&(len as u32).to_le_bytes())?;
+    writer.seek(SeekFrom::Start(start + 8 + len))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_tagged;
+    use crate::NcmInfo;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_tagged() {
+        let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+        file.extend(b"fmt \x02\0\0\0\x01\0");
+        file.extend(b"LIST\x08\0\0\0INFOjunk");
+        file.extend(b"data\x03\0\0\0abc\0");
+        let size = file.len() as u32 - 8;
+        file[4..8].copy_from_slice(&size.to_le_bytes());
+
+        let info = NcmInfo {
+            name: "Title".to_string(),
+            album: "Album".to_string(),
+            ..Default::default()
+        };
+        let mut output = Cursor::new(vec![]);
+        write_tagged(&mut Cursor::new(file), &mut output, &info, None).unwrap();
+        let output = output.into_inner();
+
+        let size = u32::from_le_bytes(output[4..8].try_into().unwrap());
+        assert_eq!(size as usize, output.len() - 8);
+        assert!(!output.windows(4).any(|w| w == b"junk"));
+        assert_eq!(&output[12..30], b"fmt \x02\0\0\0\x01\0data\x03\0\0\0");
+        assert!(output.windows(14).any(|w| w == b"INAM\x06\0\0\0Title\0"));
+
+        let id3_start = output.windows(4).position(|w| w == b"id3 ").unwrap() + 8;
+        let tag = id3::Tag::read_from2(Cursor::new(&output[id3_start..])).unwrap();
+        assert_eq!(id3::TagLike::title(&tag), Some("Title"));
+    }
+}
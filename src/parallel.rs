@@ -0,0 +1,127 @@
+//! Decryption of a single large file on all cores.
+//!
+//! The keystreams of NCM and legacy QMC only depend on the offset in the
+//! audio, so the audio is cut into chunks that are decrypted independently.
+//! Both files are memory-mapped and every chunk goes straight to its place in
+//! the output.
+
+use crate::error::DumpResult;
+use crate::ncmdump::NcmHeader;
+use memmap2::{Mmap, MmapMut};
+use rayon::prelude::*;
+use std::fs::File;
+use std::path::Path;
+
+const CHUNK_LEN: usize = 1 << 20;
+
+/// Decrypt the NCM file at `input` into the audio file at `output`, the same
+/// as [`NcmDump::write_to`](crate::ncmdump::NcmDump::write_to).
+pub fn decrypt_ncm_file(input: impl AsRef<Path>, output: impl AsRef<Path>) -> DumpResult<()> {
+    decrypt_ncm_file_in_chunks(input.as_ref(), output.as_ref(), CHUNK_LEN)
+}
+
+/// Decrypt the legacy QMC file at `input` into the audio file at `output`,
+/// the same as reading a [`QmcDump`](crate::qmcdump::QmcDump) to the end.
+pub fn decrypt_qmc_file(input: impl AsRef<Path>, output: impl AsRef<Path>) -> DumpResult<()> {
+    decrypt_qmc_file_in_chunks(input.as_ref(), output.as_ref(), CHUNK_LEN)
+}
+
+fn decrypt_ncm_file_in_chunks(input: &Path, output: &Path, chunk_len: usize) -> DumpResult<()> {
+    let input = map(&File::open(input)?)?;
+    let header = NcmHeader::parse(&input)?;
+    decrypt_chunks(
+        &input[header.audio_range()],
+        output,
+        chunk_len,
+        |buf, offset| header.decrypt(buf, offset),
+    )
+}
+
+fn decrypt_qmc_file_in_chunks(input: &Path, output: &Path, chunk_len: usize) -> DumpResult<()> {
+    let input = map(&File::open(input)?)?;
+    decrypt_chunks(&input, output, chunk_len, crate::qmcdump::decrypt_data)
+}
+
+fn map(file: &File) -> DumpResult<Mmap> {
+    // SAFETY: the file is only read, and changing it underneath is no worse
+    // than the garbage a concurrent writer would give `Read` too.
+    Ok(unsafe { Mmap::map(file)? })
+}
+
+/// Write `audio` to `output`, decrypting it a chunk at a time on the thread
+/// pool.
+fn decrypt_chunks(
+    audio: &[u8],
+    output: &Path,
+    chunk_len: usize,
+    decrypt: impl Fn(&mut [u8], usize) + Sync,
+) -> DumpResult<()> {
+    let output = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+    output.set_len(audio.len() as u64)?;
+    if audio.is_empty() {
+        return Ok(());
+    }
+
+    // SAFETY: the file was just truncated and resized by us.
+    let mut mapped = unsafe { MmapMut::map_mut(&output)? };
+    mapped
+        .par_chunks_mut(chunk_len)
+        .zip(audio.par_chunks(chunk_len))
+        .enumerate()
+        .for_each(|(index, (chunk, encrypted))| {
+            chunk.copy_from_slice(encrypted);
+            decrypt(chunk, index * chunk_len);
+        });
+    mapped.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decrypt_ncm_file, decrypt_ncm_file_in_chunks, decrypt_qmc_file_in_chunks};
+    use crate::ncmdump::NcmDump;
+    use crate::qmcdump::QmcDump;
+    use std::fs::File;
+    use std::io::{Cursor, Read};
+    use std::path::Path;
+
+    #[test]
+    fn test_decrypt_ncm_file() {
+        let mut dump = NcmDump::from_reader(File::open("./tests/test.ncm").unwrap()).unwrap();
+        let mut expected = vec![];
+        dump.write_to(&mut expected).unwrap();
+
+        let output = Path::new("./tests/test_parallel_ncm.flac");
+        for chunk_len in [1000, 4096, 1 << 20] {
+            decrypt_ncm_file_in_chunks(Path::new("./tests/test.ncm"), output, chunk_len).unwrap();
+            assert!(std::fs::read(output).unwrap() == expected);
+        }
+        decrypt_ncm_file("./tests/test.ncm", output).unwrap();
+        assert!(std::fs::read(output).unwrap() == expected);
+        std::fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_qmc_file() {
+        let file: Vec<u8> = (0..0x30000u32).map(|n| (n * 7) as u8).collect();
+        let input = Path::new("./tests/test_parallel.qmcflac");
+        std::fs::write(input, &file).unwrap();
+        let mut expected = vec![];
+        QmcDump::from_reader(Cursor::new(&file))
+            .read_to_end(&mut expected)
+            .unwrap();
+
+        let output = Path::new("./tests/test_parallel_qmc.flac");
+        for chunk_len in [1000, 0x7FFF, 0x10000] {
+            decrypt_qmc_file_in_chunks(input, output, chunk_len).unwrap();
+            assert!(std::fs::read(output).unwrap() == expected);
+        }
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -1,7 +1,35 @@
-use crate::MediaFormat;
-use std::io::{Read, Seek};
-
-const KEY: [u8; 256] = [
+use crate::ncmdump::xor_words;
+
+#[cfg(feature = "tokio")]
+mod async_dump;
+#[cfg(feature = "std")]
+pub(crate) mod cipher;
+#[cfg(feature = "std")]
+mod dump;
+#[cfg(feature = "std")]
+pub mod ekey;
+pub(crate) mod feed;
+#[cfg(feature = "std")]
+mod qmc2;
+#[cfg(feature = "std")]
+pub mod registry;
+#[cfg(feature = "std")]
+mod tm;
+#[cfg(feature = "tokio")]
+pub use async_dump::AsyncQmcDump;
+#[cfg(feature = "std")]
+pub use dump::QmcDump;
+#[cfg(feature = "std")]
+pub use ekey::decrypt_ekey;
+#[cfg(feature = "std")]
+pub use qmc2::Qmc2Dump;
+#[cfg(feature = "std")]
+pub use registry::{QmcCipher, QmcType};
+#[cfg(feature = "std")]
+pub use tm::TmDump;
+
+/// The static key of legacy QMC files, for [`get_mask`].
+pub const KEY: [u8; 256] = [
     0x77, 0x48, 0x32, 0x73, 0xDE, 0xF2, 0xC0, 0xC8, 0x95, 0xEC, 0x30, 0xB2, 0x51, 0xC3, 0xE1, 0xA0,
     0x9E, 0xE6, 0x9D, 0xCF, 0xFA, 0x7F, 0x14, 0xD1, 0xCE, 0xB8, 0xDC, 0xC3, 0x4A, 0x67, 0x93, 0xD6,
     0x28, 0xC2, 0x91, 0x70, 0xCA, 0x8D, 0xA2, 0xA4, 0xF0, 0x08, 0x61, 0x90, 0x7E, 0x6F, 0xA2, 0xE0,
@@ -20,7 +48,12 @@ const KEY: [u8; 256] = [
     0x1C, 0x71, 0xDB, 0x00, 0xBC, 0xFD, 0x0C, 0x6C, 0xA5, 0x47, 0xF7, 0xF6, 0x00, 0x79, 0x4A, 0x11,
 ];
 
-fn get_mask(offset: usize, keybox: &[u8]) -> u8 {
+/// The mask repeats every `MASK_PERIOD` bytes, except at offset
+/// `MASK_PERIOD` itself.
+const MASK_PERIOD: usize = 0x7FFF;
+
+/// The mask of the byte at `offset` of the file, computed on its own.
+pub fn get_mask(offset: usize, keybox: &[u8]) -> u8 {
     assert_eq!(keybox.len(), 256);
     let index = if offset > 0x7FFF {
         offset % 0x7FFF
@@ -31,66 +64,48 @@ fn get_mask(offset: usize, keybox: &[u8]) -> u8 {
     keybox[index]
 }
 
-pub struct QmcDump<R: Read> {
-    reader: R,
-    cursor: usize,
-    format: MediaFormat,
-}
-
-impl<R: Read> QmcDump<R> {
-    pub fn from_reader(reader: R) -> Self {
-        Self {
-            reader,
-            cursor: 0usize,
-            format: MediaFormat::Unknown,
-        }
-    }
-
-    pub fn from_reader_with_format(reader: R, format: MediaFormat) -> Self {
-        Self {
-            reader,
-            cursor: 0usize,
-            format,
-        }
-    }
-}
-
-impl<R: Read> Read for QmcDump<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let size = self.reader.read(buf)?;
-        for (index, byte) in buf.iter_mut().enumerate().take(size) {
-            let mask = get_mask(self.cursor + index, &KEY);
-            *byte ^= mask;
-        }
-        self.cursor += size;
-        Ok(size)
-    }
-}
-
-impl<R: Read + Seek> Seek for QmcDump<R> {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        let p = self.reader.seek(pos)?;
-        self.cursor = p as usize;
-        Ok(p)
+/// The masks of a period, laid out twice so that a whole period starting
+/// anywhere in it is one slice.
+static MASK_TABLE: [u8; 2 * MASK_PERIOD] = build_mask_table();
+
+const fn build_mask_table() -> [u8; 2 * MASK_PERIOD] {
+    let mut table = [0u8; 2 * MASK_PERIOD];
+    let mut offset = 0;
+    while offset < 2 * MASK_PERIOD {
+        let index = offset % MASK_PERIOD;
+        table[offset] = KEY[(index * index + 80923) & 0xFF];
+        offset += 1;
     }
+    table
 }
 
-impl<R: Read + Seek> QmcDump<R> {
-    pub fn set_format(&mut self, format: MediaFormat) {
-        self.format = format;
+/// Decrypt `buf` in place, audio that starts at `offset` of the file. There
+/// is no header, so the whole file is audio.
+pub fn decrypt_data(buf: &mut [u8], offset: usize) {
+    let phase = offset % MASK_PERIOD;
+    let mask = &MASK_TABLE[phase..phase + MASK_PERIOD];
+    for chunk in buf.chunks_mut(MASK_PERIOD) {
+        xor_words(chunk, &mask[..chunk.len()]);
     }
 
-    pub fn get_format(&self) -> MediaFormat {
-        self.format
+    if let Some(byte) = MASK_PERIOD
+        .checked_sub(offset)
+        .and_then(|index| buf.get_mut(index))
+    {
+        *byte ^= MASK_TABLE[0] ^ get_mask(MASK_PERIOD, &KEY);
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::qmcdump::{get_mask, KEY};
-    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use crate::qmcdump::{decrypt_data, get_mask, KEY, MASK_PERIOD};
 
-    use super::QmcDump;
+    /// Decrypt byte by byte with [`get_mask`], as done before the mask table.
+    fn decrypt_data_bytewise(buf: &mut [u8], offset: usize) {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte ^= get_mask(offset + index, &KEY);
+        }
+    }
 
     #[test]
     fn test_mask() {
@@ -100,61 +115,20 @@ mod test {
     }
 
     #[test]
-    fn test_decrypt() {
-        let output: [u8; 4] = [0xC3, 0x4B, 0xD4, 0xC9];
-        let input: [u8; 4] = [0, 1, 2, 3];
-        let input = Cursor::new(input);
-        let mut dump = QmcDump::from_reader(input);
-        let mut res: [u8; 4] = [0; 4];
-
-        let size = dump.read(&mut res).unwrap();
-        assert_eq!(size, 4);
-        assert_eq!(res, output);
-    }
-
-    #[test]
-    fn test_decrypt_flac_header() {
-        // fLaC
-        let input: [u8; 4] = [0xA5, 0x06, 0xB7, 0x89];
-        let input = Cursor::new(input);
-        let output: [u8; 4] = [0x66, 0x4C, 0x61, 0x43];
-        let mut res = [0u8; 4];
-        let mut dump = QmcDump::from_reader(input);
-        let size = dump.read(&mut res).unwrap();
-        assert_eq!(size, 4);
-        assert_eq!(res, output);
-    }
-
-    #[test]
-    fn test_decrypt_id3_header() {
-        // ID3
-        let input: [u8; 3] = [0x8A, 0x0E, 0xE5];
-        let input = Cursor::new(input);
-        let output: [u8; 3] = [0x49, 0x44, 0x33];
-        let mut res = [0u8; 3];
-        let mut dump = QmcDump::from_reader(input);
-        let size = dump.read(&mut res).unwrap();
-        assert_eq!(size, 3);
-        assert_eq!(res, output);
-    }
-
-    #[test]
-    fn test_seek() {
-        let output: [u8; 4] = [0xC3, 0x4B, 0xD4, 0xC9];
-        let input: [u8; 4] = [0, 1, 2, 3];
-        let input = Cursor::new(input);
-        let mut dump = QmcDump::from_reader(input);
-        let mut res: [u8; 4] = [0; 4];
-
-        let size = dump.read(&mut res).unwrap();
-        assert_eq!(size, 4);
-        assert_eq!(res, output);
-
-        dump.seek(SeekFrom::Start(0)).unwrap();
-        let mut res: [u8; 4] = [0; 4];
-
-        let size = dump.read(&mut res).unwrap();
-        assert_eq!(size, 4);
-        assert_eq!(res, output);
+    fn test_mask_table() {
+        let data: Vec<u8> = (0..3 * MASK_PERIOD).map(|n| (n * 13) as u8).collect();
+        let mut expected = data.clone();
+        decrypt_data_bytewise(&mut expected, 0);
+
+        let mut res = data.clone();
+        decrypt_data(&mut res, 0);
+        assert!(res == expected);
+
+        // Around the odd one out, and across periods
+        for (offset, len) in [(1, 0x7FFE), (0x7FF0, 0x20), (0x7FFF, 5), (0x8000, 0x9000)] {
+            let mut res = data[offset..offset + len].to_vec();
+            decrypt_data(&mut res, offset);
+            assert!(res == expected[offset..offset + len], "{offset:#x}");
+        }
     }
 }
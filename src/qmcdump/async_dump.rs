@@ -0,0 +1,89 @@
+//! Legacy QMC decoding over tokio's async IO.
+
+use super::decrypt_data;
+use crate::MediaFormat;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+pub struct AsyncQmcDump<R> {
+    reader: R,
+    cursor: usize,
+    format: MediaFormat,
+}
+
+impl<R> AsyncQmcDump<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self::from_reader_with_format(reader, MediaFormat::Unknown)
+    }
+
+    pub fn from_reader_with_format(reader: R, format: MediaFormat) -> Self {
+        Self {
+            reader,
+            cursor: 0,
+            format,
+        }
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        self.format
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncQmcDump<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.reader).poll_read(cx, buf))?;
+
+        let read = &mut buf.filled_mut()[filled..];
+        decrypt_data(read, this.cursor);
+        this.cursor += read.len();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for AsyncQmcDump<R> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        Pin::new(&mut self.get_mut().reader).start_seek(position)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        let pos = ready!(Pin::new(&mut this.reader).poll_complete(cx))?;
+        this.cursor = pos as usize;
+        Poll::Ready(Ok(pos))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncQmcDump;
+    use crate::qmcdump::QmcDump;
+    use std::io::{Cursor, Read, SeekFrom};
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_read() {
+        let file: Vec<u8> = (0..0x10000u32).map(|n| (n * 7) as u8).collect();
+        let mut expected = vec![];
+        QmcDump::from_reader(Cursor::new(&file))
+            .read_to_end(&mut expected)
+            .unwrap();
+
+        let mut dump = AsyncQmcDump::from_reader(Cursor::new(&file));
+        let mut res = vec![];
+        dump.read_to_end(&mut res).await.unwrap();
+        assert!(res == expected);
+
+        dump.seek(SeekFrom::Start(0x8000)).await.unwrap();
+        let mut res = [0u8; 100];
+        dump.read_exact(&mut res).await.unwrap();
+        assert_eq!(res, expected[0x8000..0x8064]);
+    }
+}
@@ -0,0 +1,258 @@
+const MAP_KEY_LIMIT: usize = 300;
+
+const RC4_SEGMENT_SIZE: usize = 5120;
+const RC4_FIRST_SEGMENT_SIZE: usize = 128;
+
+/// Keystream ciphers used by QMCv2. Both are offset-addressable, so a stream
+/// can be decrypted from any position.
+pub enum Qmc2Cipher {
+    Map(MapCipher),
+    Rc4(Rc4Cipher),
+}
+
+impl Qmc2Cipher {
+    pub fn new(key: Vec<u8>) -> Self {
+        if key.len() > MAP_KEY_LIMIT {
+            Self::Rc4(Rc4Cipher::new(key))
+        } else {
+            Self::Map(MapCipher::new(key))
+        }
+    }
+
+    pub fn decrypt(&mut self, buf: &mut [u8], offset: usize) {
+        match self {
+            Self::Map(c) => c.decrypt(buf, offset),
+            Self::Rc4(c) => c.decrypt(buf, offset),
+        }
+    }
+}
+
+pub struct MapCipher {
+    key: Vec<u8>,
+}
+
+impl MapCipher {
+    pub fn new(key: Vec<u8>) -> Self {
+        assert!(!key.is_empty());
+        Self { key }
+    }
+
+    fn get_mask(&self, offset: usize) -> u8 {
+        let offset = if offset > 0x7FFF {
+            offset % 0x7FFF
+        } else {
+            offset
+        };
+        let index = (offset * offset + 71214) % self.key.len();
+        let value = self.key[index];
+        let shift = ((index & 0x7) + 4) % 8;
+        (value << shift) | (value >> shift)
+    }
+
+    pub fn decrypt(&self, buf: &mut [u8], offset: usize) {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.get_mask(offset + index);
+        }
+    }
+}
+
+pub struct Rc4Cipher {
+    key: Vec<u8>,
+    key_box: Vec<u8>,
+    hash: u32,
+    state: Option<Rc4State>,
+}
+
+/// Keystream state inside one segment, kept so that sequential reads resume
+/// where the previous one stopped instead of replaying the whole segment.
+struct Rc4State {
+    segment: usize,
+    position: usize,
+    key_box: Vec<u8>,
+    j: usize,
+    k: usize,
+}
+
+impl Rc4State {
+    fn next(&mut self) -> u8 {
+        let n = self.key_box.len();
+        self.j = (self.j + 1) % n;
+        self.k = (self.key_box[self.j] as usize + self.k) % n;
+        self.key_box.swap(self.j, self.k);
+        self.key_box[(self.key_box[self.j] as usize + self.key_box[self.k] as usize) % n]
+    }
+}
+
+impl Rc4Cipher {
+    pub fn new(key: Vec<u8>) -> Self {
+        let n = key.len();
+        assert!(n > 0);
+
+        let mut key_box: Vec<u8> = (0..n).map(|i| i as u8).collect();
+        let mut j = 0usize;
+        for i in 0..n {
+            j = (j + key_box[i] as usize + key[i] as usize) % n;
+            key_box.swap(i, j);
+        }
+
+        let mut hash = 1u32;
+        for &k in key.iter().filter(|&&k| k != 0) {
+            let next = hash.wrapping_mul(k as u32);
+            if next == 0 || next <= hash {
+                break;
+            }
+            hash = next;
+        }
+
+        Self {
+            key,
+            key_box,
+            hash,
+            state: None,
+        }
+    }
+
+    fn segment_key(&self, id: usize) -> usize {
+        let n = self.key.len();
+        let seed = self.key[id % n] as f64;
+        let index = (self.hash as f64 / ((id + 1) as f64 * seed) * 100.0) as u64;
+        (index % n as u64) as usize
+    }
+
+    fn decrypt_first_segment(&self, buf: &mut [u8], offset: usize) {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.key[self.segment_key(offset + index)];
+        }
+    }
+
+    fn segment_state(&mut self, segment: usize, position: usize) -> Rc4State {
+        match self.state.take() {
+            Some(state) if state.segment == segment && state.position <= position => state,
+            _ => {
+                let mut state = Rc4State {
+                    segment,
+                    position: 0,
+                    key_box: self.key_box.clone(),
+                    j: 0,
+                    k: 0,
+                };
+                for _ in 0..self.segment_key(segment) {
+                    state.next();
+                }
+                state
+            }
+        }
+    }
+
+    fn decrypt_segment(&mut self, buf: &mut [u8], offset: usize) {
+        let position = offset % RC4_SEGMENT_SIZE;
+        let mut state = self.segment_state(offset / RC4_SEGMENT_SIZE, position);
+
+        for _ in state.position..position {
+            state.next();
+        }
+        for byte in buf.iter_mut() {
+            *byte ^= state.next();
+        }
+
+        state.position = position + buf.len();
+        self.state = Some(state);
+    }
+
+    pub fn decrypt(&mut self, buf: &mut [u8], offset: usize) {
+        let mut offset = offset;
+        let mut rest = buf;
+
+        if offset < RC4_FIRST_SEGMENT_SIZE {
+            let size = rest.len().min(RC4_FIRST_SEGMENT_SIZE - offset);
+            let (head, tail) = rest.split_at_mut(size);
+            self.decrypt_first_segment(head, offset);
+            offset += size;
+            rest = tail;
+        }
+
+        while !rest.is_empty() {
+            let size = rest.len().min(RC4_SEGMENT_SIZE - offset % RC4_SEGMENT_SIZE);
+            let (head, tail) = rest.split_at_mut(size);
+            self.decrypt_segment(head, offset);
+            offset += size;
+            rest = tail;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Qmc2Cipher;
+
+    fn check_offset_independence(cipher: &mut Qmc2Cipher) {
+        let mut whole = vec![0u8; 3 * 5120 + 77];
+        cipher.decrypt(&mut whole, 0);
+
+        for step in [100, 128, 5120, 7000] {
+            let mut parts = vec![0u8; whole.len()];
+            for (index, chunk) in parts.chunks_mut(step).enumerate() {
+                cipher.decrypt(chunk, index * step);
+            }
+            assert_eq!(parts, whole);
+
+            let mut parts = vec![0u8; whole.len()];
+            for (index, chunk) in parts.chunks_mut(step).enumerate().rev() {
+                cipher.decrypt(chunk, index * step);
+            }
+            assert_eq!(parts, whole);
+        }
+    }
+
+    #[test]
+    fn test_map_cipher() {
+        let key: Vec<u8> = (0..256).map(|n| (n * 31 + 17) as u8).collect();
+        let mut cipher = Qmc2Cipher::new(key);
+        assert!(matches!(cipher, Qmc2Cipher::Map(_)));
+        check_offset_independence(&mut cipher);
+
+        let mut buf = *b"fLaC";
+        cipher.decrypt(&mut buf, 0);
+        cipher.decrypt(&mut buf, 0);
+        assert_eq!(&buf, b"fLaC");
+    }
+
+    #[test]
+    fn test_rc4_cipher() {
+        let key: Vec<u8> = (0..512).map(|n| (n * 13 + 5) as u8).collect();
+        let mut cipher = Qmc2Cipher::new(key);
+        assert!(matches!(cipher, Qmc2Cipher::Rc4(_)));
+        check_offset_independence(&mut cipher);
+    }
+
+    #[test]
+    fn test_map_cipher_known_answer() {
+        let mut cipher = Qmc2Cipher::new(b"ncmpwn-known-answer-key!".to_vec());
+        let mut buf = [0u8; 16];
+        cipher.decrypt(&mut buf, 0x7FF8);
+        assert_eq!(
+            buf,
+            [
+                0xBF, 0x5D, 0xC1, 0x9E, 0xFE, 0x5D, 0x81, 0x5D, 0x5D, 0xC1, 0x9E, 0xFE, 0x5D, 0x81,
+                0x5D, 0xFE
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rc4_cipher_known_answer() {
+        let key: Vec<u8> = (0..512).map(|n| (n * 13 + 5) as u8).collect();
+        let mut cipher = Qmc2Cipher::new(key);
+        let cases: [(usize, [u8; 8]); 4] = [
+            (0, [0x05, 0x20, 0x6B, 0x69, 0xBC, 0x88, 0xE4, 0x7E]),
+            (5120, [0xC4, 0x03, 0x24, 0x5C, 0x7D, 0x09, 0x15, 0x77]),
+            (5125, [0x09, 0x15, 0x77, 0xE1, 0xDD, 0x3E, 0xF8, 0x70]),
+            (12345, [0x49, 0x6D, 0x28, 0x06, 0x82, 0x4E, 0x7B, 0x68]),
+        ];
+        for (offset, expected) in cases {
+            let mut buf = [0u8; 8];
+            cipher.decrypt(&mut buf, offset);
+            assert_eq!(buf, expected, "offset {offset}");
+        }
+    }
+}
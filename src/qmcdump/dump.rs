@@ -0,0 +1,145 @@
+//! [`QmcDump`], the legacy QMC decoder over a reader.
+
+use super::decrypt_data;
+use crate::decryptor::Decryptor;
+use crate::error::DumpResult;
+use crate::MediaFormat;
+use std::io::{Read, Seek};
+
+pub struct QmcDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    format: MediaFormat,
+}
+
+impl<R: Read> QmcDump<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self {
+            reader,
+            cursor: 0usize,
+            format: MediaFormat::Unknown,
+        }
+    }
+
+    pub fn from_reader_with_format(reader: R, format: MediaFormat) -> Self {
+        Self {
+            reader,
+            cursor: 0usize,
+            format,
+        }
+    }
+}
+
+impl<R: Read> Read for QmcDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        decrypt_data(&mut buf[..size], self.cursor);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for QmcDump<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let p = self.reader.seek(pos)?;
+        self.cursor = p as usize;
+        Ok(p)
+    }
+}
+
+impl<R: Read + Seek> QmcDump<R> {
+    pub fn set_format(&mut self, format: MediaFormat) {
+        self.format = format;
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        self.format
+    }
+}
+
+impl<R: Read + Seek> Decryptor for QmcDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.format)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::QmcDump;
+    use crate::qmcdump::decrypt_data;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    #[test]
+    fn test_decrypt() {
+        let output: [u8; 4] = [0xC3, 0x4B, 0xD4, 0xC9];
+        let input: [u8; 4] = [0, 1, 2, 3];
+        let input = Cursor::new(input);
+        let mut dump = QmcDump::from_reader(input);
+        let mut res: [u8; 4] = [0; 4];
+
+        let size = dump.read(&mut res).unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(res, output);
+    }
+
+    #[test]
+    fn test_decrypt_flac_header() {
+        // fLaC
+        let input: [u8; 4] = [0xA5, 0x06, 0xB7, 0x89];
+        let input = Cursor::new(input);
+        let output: [u8; 4] = [0x66, 0x4C, 0x61, 0x43];
+        let mut res = [0u8; 4];
+        let mut dump = QmcDump::from_reader(input);
+        let size = dump.read(&mut res).unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(res, output);
+    }
+
+    #[test]
+    fn test_decrypt_id3_header() {
+        // ID3
+        let input: [u8; 3] = [0x8A, 0x0E, 0xE5];
+        let input = Cursor::new(input);
+        let output: [u8; 3] = [0x49, 0x44, 0x33];
+        let mut res = [0u8; 3];
+        let mut dump = QmcDump::from_reader(input);
+        let size = dump.read(&mut res).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(res, output);
+    }
+
+    #[test]
+    fn test_seek() {
+        let output: [u8; 4] = [0xC3, 0x4B, 0xD4, 0xC9];
+        let input: [u8; 4] = [0, 1, 2, 3];
+        let input = Cursor::new(input);
+        let mut dump = QmcDump::from_reader(input);
+        let mut res: [u8; 4] = [0; 4];
+
+        let size = dump.read(&mut res).unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(res, output);
+
+        dump.seek(SeekFrom::Start(0)).unwrap();
+        let mut res: [u8; 4] = [0; 4];
+
+        let size = dump.read(&mut res).unwrap();
+        assert_eq!(size, 4);
+        assert_eq!(res, output);
+    }
+
+    #[test]
+    fn test_seek_anywhere() {
+        let data: Vec<u8> = (0..0x20000u32).map(|n| (n * 7) as u8).collect();
+        let mut expected = data.clone();
+        decrypt_data(&mut expected, 0);
+
+        let mut dump = QmcDump::from_reader(Cursor::new(&data));
+        for offset in [0x12345, 0x7FFF, 3, 0x7FFE, 0xFFFE, 0x1FFF0] {
+            dump.seek(SeekFrom::Start(offset as u64)).unwrap();
+            let mut res = vec![];
+            (&mut dump).take(0x100).read_to_end(&mut res).unwrap();
+            assert!(res == expected[offset..(offset + 0x100).min(data.len())]);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use crate::error::{DumpResult, Error};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+const DELTA: u32 = 0x9E37_79B9;
+const ROUNDS: u32 = 16;
+
+const SALT_LEN: usize = 2;
+const ZERO_LEN: usize = 7;
+
+// |tan(106 + i * 0.1)| * 100
+const SIMPLE_KEY: [u8; 8] = [0x69, 0x56, 0x46, 0x38, 0x2B, 0x20, 0x15, 0x0B];
+
+const V2_PREFIX: &[u8] = b"QQMusic EncV2,Key:";
+
+// "386ZJY!@#*$%^&)("
+const V2_KEY_1: [u8; 16] = [
+    0x33, 0x38, 0x36, 0x5A, 0x4A, 0x59, 0x21, 0x40, 0x23, 0x2A, 0x24, 0x25, 0x5E, 0x26, 0x29, 0x28,
+];
+
+// "**#!(#$%&^a1cZ,T"
+const V2_KEY_2: [u8; 16] = [
+    0x2A, 0x2A, 0x23, 0x21, 0x28, 0x23, 0x24, 0x25, 0x26, 0x5E, 0x61, 0x31, 0x63, 0x5A, 0x2C, 0x54,
+];
+
+fn tea_key(key: &[u8]) -> [u32; 4] {
+    let mut k = [0u32; 4];
+    for (i, chunk) in key.chunks_exact(4).take(4).enumerate() {
+        k[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    k
+}
+
+fn tea_decrypt_block(block: &mut [u8], k: &[u32; 4]) {
+    let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+    let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+    let mut sum = DELTA.wrapping_mul(ROUNDS);
+
+    for _ in 0..ROUNDS {
+        v1 = v1.wrapping_sub(
+            (v0 << 4).wrapping_add(k[2]) ^ v0.wrapping_add(sum) ^ (v0 >> 5).wrapping_add(k[3]),
+        );
+        v0 = v0.wrapping_sub(
+            (v1 << 4).wrapping_add(k[0]) ^ v1.wrapping_add(sum) ^ (v1 >> 5).wrapping_add(k[1]),
+        );
+        sum = sum.wrapping_sub(DELTA);
+    }
+
+    block[..4].copy_from_slice(&v0.to_be_bytes());
+    block[4..8].copy_from_slice(&v1.to_be_bytes());
+}
+
+/// Tencent's TEA-CBC variant: every block is chained with both the previous
+/// ciphertext and the previous intermediate value, and the plaintext is framed
+/// by random padding, a 2-byte salt and 7 trailing zero bytes.
+fn decrypt_tencent_tea(buf: &[u8], key: &[u8]) -> DumpResult<Vec<u8>> {
+    if !buf.len().is_multiple_of(8) || buf.len() < 16 {
+        return Err(Error::EKeyDecodeError);
+    }
+    let k = tea_key(key);
+
+    let mut dest = [0u8; 8];
+    dest.copy_from_slice(&buf[..8]);
+    tea_decrypt_block(&mut dest, &k);
+
+    let pad_len = (dest[0] & 0x7) as usize;
+    let out_len = buf
+        .len()
+        .checked_sub(1 + pad_len + SALT_LEN + ZERO_LEN)
+        .ok_or(Error::EKeyDecodeError)?;
+
+    let mut out = Vec::with_capacity(out_len);
+    let mut iv_prev = [0u8; 8];
+    let mut iv_cur = &buf[..8];
+    let mut pos = 8;
+    let mut index = 1 + pad_len;
+
+    let mut next_block = |dest: &mut [u8; 8], iv_prev: &mut [u8; 8], index: &mut usize| {
+        iv_prev.copy_from_slice(iv_cur);
+        iv_cur = &buf[pos..pos + 8];
+        dest.iter_mut().zip(iv_cur).for_each(|(d, c)| *d ^= c);
+        tea_decrypt_block(dest, &k);
+        pos += 8;
+        *index = 0;
+    };
+
+    let mut salt = 0;
+    while salt < SALT_LEN {
+        if index < 8 {
+            index += 1;
+            salt += 1;
+        } else {
+            next_block(&mut dest, &mut iv_prev, &mut index);
+        }
+    }
+
+    while out.len() < out_len {
+        if index < 8 {
+            out.push(dest[index] ^ iv_prev[index]);
+            index += 1;
+        } else {
+            next_block(&mut dest, &mut iv_prev, &mut index);
+        }
+    }
+
+    for _ in 0..ZERO_LEN {
+        if index == 8 {
+            next_block(&mut dest, &mut iv_prev, &mut index);
+        }
+        if dest[index] != iv_prev[index] {
+            return Err(Error::EKeyDecodeError);
+        }
+        index += 1;
+    }
+
+    Ok(out)
+}
+
+fn derive_key_v1(raw: &[u8]) -> DumpResult<Vec<u8>> {
+    if raw.len() < 16 {
+        return Err(Error::EKeyDecodeError);
+    }
+
+    let mut tea_key = [0u8; 16];
+    for i in 0..8 {
+        tea_key[i << 1] = SIMPLE_KEY[i];
+        tea_key[(i << 1) + 1] = raw[i];
+    }
+
+    let mut key = raw[..8].to_vec();
+    key.extend(decrypt_tencent_tea(&raw[8..], &tea_key)?);
+    Ok(key)
+}
+
+fn derive_key_v2(raw: &[u8]) -> DumpResult<Vec<u8>> {
+    let buf = decrypt_tencent_tea(raw, &V2_KEY_1)?;
+    let buf = decrypt_tencent_tea(&buf, &V2_KEY_2)?;
+    STANDARD.decode(buf).map_err(|_| Error::EKeyDecodeError)
+}
+
+/// Decode a base64 ekey into the raw QMCv2 key.
+///
+/// Both the plain form found in file trailers and the
+/// `QQMusic EncV2,Key:` form used by newer clients are accepted, so keys can
+/// come from the file itself, the client's database or anywhere else.
+pub fn decrypt_ekey(ekey: impl AsRef<[u8]>) -> DumpResult<Vec<u8>> {
+    let ekey = ekey.as_ref();
+    let ekey = match ekey
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace() && *b != 0)
+    {
+        Some(end) => &ekey[..=end],
+        None => return Err(Error::EKeyDecodeError),
+    };
+
+    let raw = STANDARD.decode(ekey).map_err(|_| Error::EKeyDecodeError)?;
+    match raw.strip_prefix(V2_PREFIX) {
+        Some(v2) => derive_key_v1(&derive_key_v2(v2)?),
+        None => derive_key_v1(&raw),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{
+        decrypt_tencent_tea, tea_key, DELTA, ROUNDS, SIMPLE_KEY, V2_KEY_1, V2_KEY_2, V2_PREFIX,
+    };
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    fn tea_encrypt_block(block: &mut [u8], k: &[u32; 4]) {
+        let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+        let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+        let mut sum = 0u32;
+
+        for _ in 0..ROUNDS {
+            sum = sum.wrapping_add(DELTA);
+            v0 = v0.wrapping_add(
+                (v1 << 4).wrapping_add(k[0]) ^ v1.wrapping_add(sum) ^ (v1 >> 5).wrapping_add(k[1]),
+            );
+            v1 = v1.wrapping_add(
+                (v0 << 4).wrapping_add(k[2]) ^ v0.wrapping_add(sum) ^ (v0 >> 5).wrapping_add(k[3]),
+            );
+        }
+
+        block[..4].copy_from_slice(&v0.to_be_bytes());
+        block[4..8].copy_from_slice(&v1.to_be_bytes());
+    }
+
+    pub(crate) fn encrypt_tencent_tea(data: &[u8], key: &[u8]) -> Vec<u8> {
+        let k = tea_key(key);
+        let pad_len = (8 - (data.len() + 10) % 8) % 8;
+
+        let mut plain = vec![0xA8 | pad_len as u8];
+        plain.extend(std::iter::repeat_n(0x5A, pad_len + 2));
+        plain.extend(data);
+        plain.extend([0u8; 7]);
+
+        let mut out = Vec::with_capacity(plain.len());
+        let mut plain_pre = [0u8; 8];
+        let mut cipher_pre = [0u8; 8];
+        for block in plain.chunks_exact(8) {
+            let mut tmp = [0u8; 8];
+            tmp.iter_mut()
+                .zip(block.iter().zip(cipher_pre))
+                .for_each(|(t, (p, c))| *t = p ^ c);
+            let mut cipher = tmp;
+            tea_encrypt_block(&mut cipher, &k);
+            cipher.iter_mut().zip(plain_pre).for_each(|(c, p)| *c ^= p);
+            plain_pre = tmp;
+            cipher_pre = cipher;
+            out.extend(cipher);
+        }
+
+        out
+    }
+
+    pub(crate) fn encrypt_ekey(key: &[u8]) -> String {
+        let mut tea_key = [0u8; 16];
+        for i in 0..8 {
+            tea_key[i << 1] = SIMPLE_KEY[i];
+            tea_key[(i << 1) + 1] = key[i];
+        }
+
+        let mut raw = key[..8].to_vec();
+        raw.extend(encrypt_tencent_tea(&key[8..], &tea_key));
+        STANDARD.encode(raw)
+    }
+
+    #[test]
+    fn test_simple_key() {
+        let key: Vec<u8> = (0..8)
+            .map(|i| ((106.0 + i as f64 * 0.1).tan().abs() * 100.0) as u8)
+            .collect();
+        assert_eq!(key, SIMPLE_KEY);
+    }
+
+    #[test]
+    fn test_tencent_tea() {
+        let key = b"0123456789abcdef";
+        for len in 0..24 {
+            let data: Vec<u8> = (0..len).map(|n| n as u8).collect();
+            let encrypted = encrypt_tencent_tea(&data, key);
+            assert_eq!(decrypt_tencent_tea(&encrypted, key).unwrap(), data);
+        }
+
+        let mut encrypted = encrypt_tencent_tea(b"ncmpwn", key);
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0xFF;
+        assert!(decrypt_tencent_tea(&encrypted, key).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_ekey() {
+        let key: Vec<u8> = (0..256).map(|n| (n * 7 + 3) as u8).collect();
+        let ekey = encrypt_ekey(&key);
+        assert_eq!(super::decrypt_ekey(ekey.as_bytes()).unwrap(), key);
+        assert!(super::decrypt_ekey(b"not base64!").is_err());
+        assert!(super::decrypt_ekey("").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_ekey_known_answer() {
+        let ekey = "bmNtcHduLWvXC6qBxOGGoqicHpRzvE/vt0ysBZObLpTXUMQqXl+iYw==";
+        assert_eq!(
+            super::decrypt_ekey(ekey).unwrap(),
+            b"ncmpwn-known-answer-key!"
+        );
+    }
+
+    #[test]
+    fn test_decrypt_ekey_v2() {
+        let key: Vec<u8> = (0..704).map(|n| (n * 11 + 5) as u8).collect();
+        let v1 = encrypt_ekey(&key);
+        let inner = encrypt_tencent_tea(v1.as_bytes(), &V2_KEY_2);
+        let inner = encrypt_tencent_tea(&inner, &V2_KEY_1);
+
+        let mut raw = V2_PREFIX.to_vec();
+        raw.extend(inner);
+        let ekey = format!("{}\n", STANDARD.encode(raw));
+        assert_eq!(super::decrypt_ekey(&ekey).unwrap(), key);
+    }
+}
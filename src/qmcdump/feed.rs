@@ -0,0 +1,68 @@
+//! Static-cipher QMC files as pushed bytes. There is no header, so every
+//! chunk is audio right away.
+
+use super::decrypt_data;
+use crate::feed::{Event, Feed};
+use alloc::vec;
+use alloc::vec::Vec;
+
+#[derive(Default)]
+pub struct QmcFeeder {
+    cursor: usize,
+    finished: bool,
+}
+
+impl QmcFeeder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Feed for QmcFeeder {
+    fn feed(&mut self, data: &[u8]) -> Vec<Event> {
+        if self.finished || data.is_empty() {
+            return vec![];
+        }
+        let mut audio = data.to_vec();
+        decrypt_data(&mut audio, self.cursor);
+        self.cursor += audio.len();
+        vec![Event::Audio(audio)]
+    }
+
+    fn finish(&mut self) -> Vec<Event> {
+        if core::mem::replace(&mut self.finished, true) {
+            return vec![];
+        }
+        vec![Event::Finished]
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::QmcFeeder;
+    use crate::feed::{Event, Feed};
+    use crate::qmcdump::QmcDump;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_feed() {
+        let file: Vec<u8> = (0..0x10000u32).map(|n| (n * 7) as u8).collect();
+        let mut expected = vec![];
+        QmcDump::from_reader(Cursor::new(&file))
+            .read_to_end(&mut expected)
+            .unwrap();
+
+        let mut feeder = QmcFeeder::new();
+        let mut audio = vec![];
+        for chunk in file.chunks(333) {
+            for event in feeder.feed(chunk) {
+                match event {
+                    Event::Audio(chunk) => audio.extend(chunk),
+                    e => panic!("{:?}", e),
+                }
+            }
+        }
+        assert!(matches!(feeder.finish()[..], [Event::Finished]));
+        assert!(audio == expected);
+    }
+}
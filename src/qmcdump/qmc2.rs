@@ -0,0 +1,299 @@
+use super::cipher::Qmc2Cipher;
+use super::ekey::decrypt_ekey;
+use crate::decryptor::Decryptor;
+use crate::error::{DumpResult, Error};
+use crate::MediaFormat;
+use std::io::{Read, Seek, SeekFrom};
+
+const QTAG: [u8; 4] = [b'Q', b'T', b'a', b'g'];
+const STAG: [u8; 4] = [b'S', b'T', b'a', b'g'];
+const MAX_KEY_LEN: u64 = 0x300;
+
+/// Decoder for QMCv2 files (`.mflac`, `.mgg`, `.mflac0`, `.mgg1`, ...) whose
+/// ekey is stored in a trailer after the audio data.
+pub struct Qmc2Dump<R: Read> {
+    reader: R,
+    cursor: usize,
+    audio_len: u64,
+    cipher: Qmc2Cipher,
+    format: MediaFormat,
+}
+
+impl<R: Read + Seek> Qmc2Dump<R> {
+    pub fn from_reader(mut reader: R) -> DumpResult<Self> {
+        let (ekey, audio_len) = read_trailer(&mut reader)?;
+        let key = decrypt_ekey(&ekey)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            audio_len,
+            cipher: Qmc2Cipher::new(key),
+            format: MediaFormat::Unknown,
+        })
+    }
+
+    /// Build a decoder from a raw key (see [`decrypt_ekey`]) and a bare audio
+    /// stream, for files whose key is kept outside of the file. A trailing
+    /// `QTag`/`STag` block is excluded from the audio.
+    pub fn from_key(mut reader: R, key: Vec<u8>) -> DumpResult<Self> {
+        if key.is_empty() {
+            return Err(Error::EKeyDecodeError);
+        }
+        let audio_len = read_tagged_audio_len(&mut reader)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            audio_len,
+            cipher: Qmc2Cipher::new(key),
+            format: MediaFormat::Unknown,
+        })
+    }
+
+    /// Same as [`Qmc2Dump::from_key`], decoding the key from a base64 ekey.
+    pub fn from_ekey(reader: R, ekey: &str) -> DumpResult<Self> {
+        Self::from_key(reader, decrypt_ekey(ekey)?)
+    }
+
+    pub fn from_reader_with_format(reader: R, format: MediaFormat) -> DumpResult<Self> {
+        let mut dump = Self::from_reader(reader)?;
+        dump.format = format;
+        Ok(dump)
+    }
+
+    pub fn set_format(&mut self, format: MediaFormat) {
+        self.format = format;
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        self.format
+    }
+
+    pub fn audio_len(&self) -> u64 {
+        self.audio_len
+    }
+}
+
+fn read_tagged_audio_len<R: Read + Seek>(reader: &mut R) -> DumpResult<u64> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < 8 {
+        return Ok(file_len);
+    }
+
+    let mut tail = [0u8; 8];
+    reader.seek(SeekFrom::End(-8))?;
+    reader.read_exact(&mut tail)?;
+    if tail[4..] != QTAG && tail[4..] != STAG {
+        return Ok(file_len);
+    }
+
+    let payload_len = u32::from_be_bytes([tail[0], tail[1], tail[2], tail[3]]) as u64;
+    file_len
+        .checked_sub(8 + payload_len)
+        .ok_or(Error::TrailerError)
+}
+
+/// Locate the ekey at the end of the file and return it along with the
+/// length of the audio data preceding it.
+fn read_trailer<R: Read + Seek>(reader: &mut R) -> DumpResult<(Vec<u8>, u64)> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < 8 {
+        return Err(Error::TrailerError);
+    }
+
+    let mut tag = [0u8; 4];
+    reader.seek(SeekFrom::End(-4))?;
+    reader.read_exact(&mut tag)?;
+
+    if tag == QTAG {
+        // [ekey,song id,2][payload length: u32 BE]["QTag"]
+        let mut len_buf = [0u8; 4];
+        reader.seek(SeekFrom::End(-8))?;
+        reader.read_exact(&mut len_buf)?;
+        let payload_len = u32::from_be_bytes(len_buf) as u64;
+        let audio_len = file_len
+            .checked_sub(8 + payload_len)
+            .ok_or(Error::TrailerError)?;
+
+        let mut payload = vec![0u8; payload_len as usize];
+        reader.seek(SeekFrom::Start(audio_len))?;
+        reader.read_exact(&mut payload)?;
+        let ekey = payload.split(|&b| b == b',').next().unwrap_or(&[]);
+
+        Ok((ekey.to_vec(), audio_len))
+    } else if tag == STAG {
+        // The key of an STag file is kept by the client, not in the file
+        Err(Error::TrailerError)
+    } else {
+        // [ekey][ekey length: u32 LE]
+        let key_len = u32::from_le_bytes(tag) as u64;
+        if key_len == 0 || key_len > MAX_KEY_LEN {
+            return Err(Error::TrailerError);
+        }
+        let audio_len = file_len
+            .checked_sub(4 + key_len)
+            .ok_or(Error::TrailerError)?;
+
+        let mut ekey = vec![0u8; key_len as usize];
+        reader.seek(SeekFrom::Start(audio_len))?;
+        reader.read_exact(&mut ekey)?;
+        while ekey.last() == Some(&0) {
+            ekey.pop();
+        }
+
+        Ok((ekey, audio_len))
+    }
+}
+
+impl<R: Read> Read for Qmc2Dump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remain = self.audio_len.saturating_sub(self.cursor as u64);
+        let len = buf.len().min(remain.try_into().unwrap_or(usize::MAX));
+        let size = self.reader.read(&mut buf[..len])?;
+        self.cipher.decrypt(&mut buf[..size], self.cursor);
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for Qmc2Dump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => (self.cursor as u64).checked_add_signed(d),
+            SeekFrom::End(d) => self.audio_len.checked_add_signed(d),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked before the start of QMC audio",
+            )
+        })?;
+
+        self.reader.seek(SeekFrom::Start(new_pos))?;
+        self.cursor = new_pos as usize;
+        Ok(new_pos)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for Qmc2Dump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.format)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::cipher::Qmc2Cipher;
+    use super::super::ekey::test::encrypt_ekey;
+    use super::Qmc2Dump;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    fn build_file(key: &[u8], audio: &[u8], qtag: bool) -> Vec<u8> {
+        let mut data = audio.to_vec();
+        Qmc2Cipher::new(key.to_vec()).decrypt(&mut data, 0);
+
+        let ekey = encrypt_ekey(key);
+        if qtag {
+            let payload = format!("{ekey},114514,2");
+            data.extend(payload.as_bytes());
+            data.extend((payload.len() as u32).to_be_bytes());
+            data.extend(b"QTag");
+        } else {
+            data.extend(ekey.as_bytes());
+            data.extend((ekey.len() as u32).to_le_bytes());
+        }
+        data
+    }
+
+    fn audio() -> Vec<u8> {
+        let mut audio = b"fLaC".to_vec();
+        audio.extend((0..20000u32).map(|n| (n % 251) as u8));
+        audio
+    }
+
+    #[test]
+    fn test_decrypt_map() {
+        let key: Vec<u8> = (0..128).map(|n| (n * 3 + 1) as u8).collect();
+        for qtag in [true, false] {
+            let file = build_file(&key, &audio(), qtag);
+            let mut dump = Qmc2Dump::from_reader(Cursor::new(file)).unwrap();
+            let mut res = vec![];
+            dump.read_to_end(&mut res).unwrap();
+            assert_eq!(res, audio());
+        }
+    }
+
+    #[test]
+    fn test_decrypt_known_answer() {
+        let ekey = "bmNtcHduLWvXC6qBxOGGoqicHpRzvE/vt0ysBZObLpTXUMQqXl+iYw==";
+        let mut file = vec![
+            0xD9, 0x11, 0xA0, 0xDD, 0xFE, 0x5C, 0x83, 0x5E, 0xFA, 0x9B, 0xC7, 0x5A, 0xB7, 0x54,
+            0xCB, 0x95,
+        ];
+        file.extend(ekey.as_bytes());
+        file.extend((ekey.len() as u32).to_le_bytes());
+
+        let mut dump = Qmc2Dump::from_reader(Cursor::new(file)).unwrap();
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res[..4], *b"fLaC");
+        assert_eq!(res[4..], (0..12).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_decrypt_rc4() {
+        let key: Vec<u8> = (0..512).map(|n| (n * 7 + 9) as u8).collect();
+        let file = build_file(&key, &audio(), true);
+        let mut dump = Qmc2Dump::from_reader(Cursor::new(file)).unwrap();
+        assert_eq!(dump.audio_len(), audio().len() as u64);
+
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio());
+
+        dump.seek(SeekFrom::Start(5000)).unwrap();
+        let mut res = [0u8; 300];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(res[..], audio()[5000..5300]);
+
+        let pos = dump.seek(SeekFrom::End(-4)).unwrap();
+        assert_eq!(pos, audio().len() as u64 - 4);
+    }
+
+    #[test]
+    fn test_from_ekey() {
+        let key: Vec<u8> = (0..400).map(|n| (n * 5 + 2) as u8).collect();
+        let mut data = audio();
+        Qmc2Cipher::new(key.clone()).decrypt(&mut data, 0);
+
+        let mut dump = Qmc2Dump::from_ekey(Cursor::new(data), &encrypt_ekey(&key)).unwrap();
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio());
+    }
+
+    #[test]
+    fn test_from_ekey_with_stag() {
+        let key: Vec<u8> = (0..200).map(|n| (n * 3 + 7) as u8).collect();
+        let mut data = audio();
+        Qmc2Cipher::new(key.clone()).decrypt(&mut data, 0);
+        data.extend(b"114514,2");
+        data.extend(8u32.to_be_bytes());
+        data.extend(b"STag");
+
+        let mut dump = Qmc2Dump::from_ekey(Cursor::new(data), &encrypt_ekey(&key)).unwrap();
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio());
+    }
+
+    #[test]
+    fn test_bad_trailer() {
+        assert!(Qmc2Dump::from_reader(Cursor::new(vec![0u8; 64])).is_err());
+        assert!(Qmc2Dump::from_reader(Cursor::new(b"fLaCSTag".to_vec())).is_err());
+    }
+}
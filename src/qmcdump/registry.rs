@@ -0,0 +1,100 @@
+use crate::MediaFormat;
+use std::path::Path;
+
+/// How the audio of a QQ Music file is protected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QmcCipher {
+    /// The static mask of [`super::QmcDump`]
+    Static,
+    /// QMCv2, keyed by an ekey (see [`super::Qmc2Dump`])
+    Qmc2,
+    /// Only the m4a header is scrambled (see [`super::TmDump`])
+    TmHeader,
+    /// Not encrypted at all
+    Plain,
+}
+
+#[derive(Clone, Copy)]
+pub struct QmcType {
+    pub cipher: QmcCipher,
+    pub format: MediaFormat,
+}
+
+const REGISTRY: &[(&str, QmcCipher, MediaFormat)] = &[
+    ("qmc0", QmcCipher::Static, MediaFormat::ID3v2),
+    ("qmc2", QmcCipher::Static, MediaFormat::M4A),
+    ("qmc3", QmcCipher::Static, MediaFormat::ID3v2),
+    ("qmc4", QmcCipher::Static, MediaFormat::M4A),
+    ("qmc6", QmcCipher::Static, MediaFormat::M4A),
+    ("qmc8", QmcCipher::Static, MediaFormat::M4A),
+    ("qmcogg", QmcCipher::Static, MediaFormat::Ogg),
+    ("qmcflac", QmcCipher::Static, MediaFormat::fLaC),
+    ("bkcmp3", QmcCipher::Static, MediaFormat::ID3v2),
+    ("bkcm4a", QmcCipher::Static, MediaFormat::M4A),
+    ("bkcflac", QmcCipher::Static, MediaFormat::fLaC),
+    ("bkcwav", QmcCipher::Static, MediaFormat::WAV),
+    ("bkcape", QmcCipher::Static, MediaFormat::APE),
+    ("bkcogg", QmcCipher::Static, MediaFormat::Ogg),
+    ("bkcwma", QmcCipher::Static, MediaFormat::Unsupported),
+    ("tkm", QmcCipher::Static, MediaFormat::M4A),
+    ("mflac", QmcCipher::Qmc2, MediaFormat::fLaC),
+    ("mflac0", QmcCipher::Qmc2, MediaFormat::fLaC),
+    ("mflach", QmcCipher::Qmc2, MediaFormat::fLaC),
+    ("mgg", QmcCipher::Qmc2, MediaFormat::Ogg),
+    ("mgg1", QmcCipher::Qmc2, MediaFormat::Ogg),
+    ("mggl", QmcCipher::Qmc2, MediaFormat::Ogg),
+    ("mmp4", QmcCipher::Qmc2, MediaFormat::M4A),
+    ("tm2", QmcCipher::TmHeader, MediaFormat::M4A),
+    ("tm6", QmcCipher::TmHeader, MediaFormat::M4A),
+    ("tm0", QmcCipher::Plain, MediaFormat::ID3v2),
+    ("tm3", QmcCipher::Plain, MediaFormat::ID3v2),
+];
+
+/// Look up the cipher and container of a QQ Music file by its extension,
+/// e.g. `"qmcflac"`.
+pub fn guess_from_ext(ext: &str) -> Option<QmcType> {
+    let ext = ext.to_ascii_lowercase();
+    REGISTRY
+        .iter()
+        .find(|(e, _, _)| *e == ext)
+        .map(|&(_, cipher, format)| QmcType { cipher, format })
+}
+
+pub fn guess_from_path(path: &Path) -> Option<QmcType> {
+    guess_from_ext(path.extension()?.to_str()?)
+}
+
+/// Every extension in the registry.
+pub fn extensions() -> impl Iterator<Item = &'static str> {
+    REGISTRY.iter().map(|(e, _, _)| *e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{extensions, guess_from_ext, guess_from_path, QmcCipher};
+    use crate::MediaFormat;
+    use std::path::Path;
+
+    #[test]
+    fn test_lookup() {
+        let t = guess_from_ext("bkcflac").unwrap();
+        assert_eq!(t.cipher, QmcCipher::Static);
+        assert!(matches!(t.format, MediaFormat::fLaC));
+
+        let t = guess_from_path(Path::new("./cache/song.TM6")).unwrap();
+        assert_eq!(t.cipher, QmcCipher::TmHeader);
+        assert!(matches!(t.format, MediaFormat::M4A));
+
+        let t = guess_from_ext("qmc2").unwrap();
+        assert_eq!(t.cipher, QmcCipher::Static);
+        assert!(matches!(t.format, MediaFormat::M4A));
+
+        let t = guess_from_ext("mmp4").unwrap();
+        assert_eq!(t.cipher, QmcCipher::Qmc2);
+        assert!(matches!(t.format, MediaFormat::M4A));
+
+        assert!(guess_from_ext("ncm").is_none());
+        assert!(guess_from_path(Path::new("song")).is_none());
+        assert!(extensions().all(|e| guess_from_ext(e).is_some()));
+    }
+}
@@ -0,0 +1,73 @@
+use crate::decryptor::Decryptor;
+use crate::error::DumpResult;
+use crate::MediaFormat;
+use std::io::{Read, Seek, SeekFrom};
+
+/// The start of an MP4 `ftyp` box, which QQ Music overwrites in its cache.
+const HEADER: [u8; 8] = [0x00, 0x00, 0x00, 0x20, b'f', b't', b'y', b'p'];
+
+/// Reader for QQ Music's `.tm2`/`.tm6` cache files: plain m4a files whose
+/// first 8 bytes have been scrambled.
+pub struct TmDump<R: Read> {
+    reader: R,
+    cursor: usize,
+}
+
+impl<R: Read> TmDump<R> {
+    pub fn from_reader(reader: R) -> Self {
+        Self { reader, cursor: 0 }
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        MediaFormat::M4A
+    }
+}
+
+impl<R: Read> Read for TmDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        for (index, byte) in buf.iter_mut().enumerate().take(size) {
+            if let Some(&b) = HEADER.get(self.cursor + index) {
+                *byte = b;
+            }
+        }
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for TmDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let p = self.reader.seek(pos)?;
+        self.cursor = p as usize;
+        Ok(p)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for TmDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.get_format())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TmDump;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    #[test]
+    fn test_repair_header() {
+        let mut file = vec![0xAAu8; 8];
+        file.extend(b"M4A \0\0\0\0");
+        let mut dump = TmDump::from_reader(Cursor::new(file));
+
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, b"\0\0\0\x20ftypM4A \0\0\0\0");
+
+        dump.seek(SeekFrom::Start(6)).unwrap();
+        let mut res = [0u8; 4];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(&res, b"ypM4");
+    }
+}
@@ -0,0 +1,378 @@
+//! Detection of file types. Every format, built-in or not, is a set of
+//! detectors plus a constructor registered in a [`FormatRegistry`].
+
+use crate::decryptor::{Decryptor, PlainDump};
+use crate::error::{DumpResult, Error};
+use crate::kgmdump::{KgmDump, KGM_MAGIC, VPR_MAGIC};
+use crate::kwmdump::KwmDump;
+use crate::ncmdump::{NcmCacheDump, NcmDump};
+use crate::qmcdump::{registry as qmc_registry, Qmc2Dump, QmcCipher, QmcDump, QmcType, TmDump};
+use crate::xmdump::XmDump;
+use crate::xmlydump::{XmlyDump, XmlyKey};
+use crate::{jooxdump, kwmdump, ncmdump, xmdump};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+
+/// Number of bytes at each end of a file that detectors get to see.
+pub const PROBE_LEN: usize = 64;
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// The file handed to a format constructor, at its start.
+pub type Source<'a> = Box<dyn ReadSeek + 'a>;
+
+type Constructor = dyn for<'a> Fn(Source<'a>) -> DumpResult<Box<dyn Decryptor + 'a>> + Send + Sync;
+
+/// What detectors know about a file.
+pub struct Probe<'p> {
+    /// Up to [`PROBE_LEN`] bytes from the start of the file
+    pub head: &'p [u8],
+    /// Up to [`PROBE_LEN`] bytes from the end of the file
+    pub tail: &'p [u8],
+    /// Lowercase extension of the file name hint
+    pub extension: Option<&'p str>,
+}
+
+pub enum Detector {
+    /// `magic` at `offset` bytes from the start of the file
+    Magic {
+        offset: usize,
+        magic: Vec<u8>,
+    },
+    /// The file ends with the bytes
+    Trailer(Vec<u8>),
+    /// The file name has the extension, compared case-insensitively
+    Extension(String),
+    Custom(Box<dyn Fn(&Probe) -> bool + Send + Sync>),
+}
+
+impl Detector {
+    pub fn magic(magic: &[u8]) -> Self {
+        Self::Magic {
+            offset: 0,
+            magic: magic.to_vec(),
+        }
+    }
+
+    pub fn extension(ext: &str) -> Self {
+        Self::Extension(ext.to_ascii_lowercase())
+    }
+
+    pub fn matches(&self, probe: &Probe) -> bool {
+        match self {
+            Self::Magic { offset, magic } => probe
+                .head
+                .get(*offset..)
+                .is_some_and(|head| head.starts_with(magic)),
+            Self::Trailer(trailer) => probe.tail.ends_with(trailer),
+            Self::Extension(ext) => probe.extension == Some(ext.as_str()),
+            Self::Custom(check) => check(probe),
+        }
+    }
+}
+
+pub struct Format {
+    name: String,
+    detectors: Vec<Detector>,
+    constructor: Box<Constructor>,
+}
+
+impl Format {
+    /// A format matching a file when any of `detectors` does.
+    pub fn new<F>(name: &str, detectors: Vec<Detector>, constructor: F) -> Self
+    where
+        F: for<'a> Fn(Source<'a>) -> DumpResult<Box<dyn Decryptor + 'a>> + Send + Sync + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            detectors,
+            constructor: Box::new(constructor),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn matches(&self, probe: &Probe) -> bool {
+        self.detectors.iter().any(|d| d.matches(probe))
+    }
+}
+
+/// Formats in the order they are tried. Formats registered by users come
+/// first, so they can take over files a built-in format would claim.
+#[derive(Default)]
+pub struct FormatRegistry {
+    formats: Vec<Format>,
+    registered: usize,
+}
+
+impl FormatRegistry {
+    /// An empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry of every format `ncmpwn` supports on its own.
+    pub fn with_builtins() -> Self {
+        let mut formats = vec![
+            Format::new("ncm", vec![Detector::magic(&ncmdump::FORMAT)], |r| {
+                Ok(Box::new(NcmDump::from_reader(r)?))
+            }),
+            Format::new(
+                "kgm",
+                vec![Detector::magic(&KGM_MAGIC), Detector::magic(&VPR_MAGIC)],
+                |r| Ok(Box::new(KgmDump::from_reader(r)?)),
+            ),
+            Format::new(
+                "kwm",
+                vec![
+                    Detector::magic(kwmdump::MAGIC),
+                    Detector::magic(kwmdump::MAGIC_LEGACY),
+                ],
+                |r| Ok(Box::new(KwmDump::from_reader(r)?)),
+            ),
+            Format::new("xm", vec![Detector::magic(xmdump::MAGIC)], |r| {
+                Ok(Box::new(XmDump::from_reader(r)?))
+            }),
+            // The key is derived from a device UUID the file does not carry
+            Format::new("joox", vec![Detector::magic(jooxdump::MAGIC)], |_| {
+                Err(Error::KeyLoadError)
+            }),
+            Format::new("x2m", vec![Detector::extension("x2m")], |r| {
+                Ok(Box::new(XmlyDump::from_reader(r, &XmlyKey::x2m())?))
+            }),
+            Format::new("x3m", vec![Detector::extension("x3m")], |r| {
+                Ok(Box::new(XmlyDump::from_reader(r, &XmlyKey::x3m())?))
+            }),
+            Format::new("uc!", vec![Detector::extension("uc!")], |r| {
+                Ok(Box::new(NcmCacheDump::from_reader(r)))
+            }),
+        ];
+
+        for ext in qmc_registry::extensions() {
+            let qmc_type = qmc_registry::guess_from_ext(ext).expect("registered QMC extension");
+            formats.push(Format::new(ext, vec![Detector::extension(ext)], move |r| {
+                open_qmc(r, qmc_type)
+            }));
+        }
+
+        // Files without an extension to go by may still carry a QMCv2 key
+        formats.push(Format::new(
+            "qmc2",
+            vec![
+                Detector::Trailer(b"QTag".to_vec()),
+                Detector::Custom(Box::new(has_qmc2_key_len)),
+            ],
+            |r| Ok(Box::new(Qmc2Dump::from_reader(r)?)),
+        ));
+
+        Self {
+            formats,
+            registered: 0,
+        }
+    }
+
+    /// The registry used by [`crate::open_any`].
+    pub fn global() -> &'static RwLock<FormatRegistry> {
+        static GLOBAL: OnceLock<RwLock<FormatRegistry>> = OnceLock::new();
+        GLOBAL.get_or_init(|| RwLock::new(Self::with_builtins()))
+    }
+
+    /// Add a format, tried before the built-in ones and after those
+    /// registered earlier.
+    pub fn register(&mut self, format: Format) {
+        self.formats.insert(self.registered, format);
+        self.registered += 1;
+    }
+
+    pub fn formats(&self) -> impl Iterator<Item = &Format> {
+        self.formats.iter()
+    }
+
+    /// Find the format of the file. The reader is moved back to the start.
+    pub fn detect<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        filename_hint: Option<&str>,
+    ) -> DumpResult<Option<&Format>> {
+        let mut head = vec![];
+        reader
+            .by_ref()
+            .take(PROBE_LEN as u64)
+            .read_to_end(&mut head)?;
+
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let tail_len = file_len.min(PROBE_LEN as u64);
+        let mut tail = vec![0u8; tail_len as usize];
+        reader.seek(SeekFrom::End(-(tail_len as i64)))?;
+        reader.read_exact(&mut tail)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let extension = filename_hint
+            .and_then(|name| Path::new(name).extension())
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        let probe = Probe {
+            head: &head,
+            tail: &tail,
+            extension: extension.as_deref(),
+        };
+
+        Ok(self.formats.iter().find(|f| f.matches(&probe)))
+    }
+
+    /// Open the file with the first format that matches it.
+    pub fn open<'a, R: Read + Seek + 'a>(
+        &self,
+        mut reader: R,
+        filename_hint: Option<&str>,
+    ) -> DumpResult<Box<dyn Decryptor + 'a>> {
+        let format = self
+            .detect(&mut reader, filename_hint)?
+            .ok_or(Error::FormatError)?;
+        (format.constructor)(Box::new(reader))
+    }
+}
+
+fn open_qmc(reader: Source<'_>, qmc_type: QmcType) -> DumpResult<Box<dyn Decryptor + '_>> {
+    let format = qmc_type.format;
+    Ok(match qmc_type.cipher {
+        QmcCipher::Static => Box::new(QmcDump::from_reader_with_format(reader, format)),
+        QmcCipher::Qmc2 => Box::new(Qmc2Dump::from_reader_with_format(reader, format)?),
+        QmcCipher::TmHeader => Box::new(TmDump::from_reader(reader)),
+        QmcCipher::Plain => Box::new(PlainDump { reader, format }),
+    })
+}
+
+/// `[ekey][ekey length: u32 LE]` with a plausible key length, where the
+/// ekey is base64 text. Only the part of the ekey inside the probe is checked.
+fn has_qmc2_key_len(probe: &Probe) -> bool {
+    let Some(end) = probe.tail.len().checked_sub(4) else {
+        return false;
+    };
+    let mut len_buf = [0u8; 4];
+    len_buf.copy_from_slice(&probe.tail[end..]);
+    let len = u32::from_le_bytes(len_buf) as usize;
+    if !(1..=0x300).contains(&len) {
+        return false;
+    }
+
+    match end.checked_sub(len) {
+        Some(start) => STANDARD.decode(&probe.tail[start..end]).is_ok(),
+        None => probe.tail[..end]
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=')),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Detector, Format, FormatRegistry, Source};
+    use crate::decryptor::{Decryptor, PlainDump};
+    use crate::MediaFormat;
+    use std::io::{Cursor, Read};
+
+    struct XorDump<'a>(Source<'a>);
+
+    impl Read for XorDump<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = self.0.read(buf)?;
+            buf[..size].iter_mut().for_each(|b| *b ^= 0x55);
+            Ok(size)
+        }
+    }
+
+    impl std::io::Seek for XorDump<'_> {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl Decryptor for XorDump<'_> {
+        fn declared_format(&mut self) -> crate::error::DumpResult<MediaFormat> {
+            Ok(MediaFormat::fLaC)
+        }
+    }
+
+    #[test]
+    fn test_builtins() {
+        let registry = FormatRegistry::with_builtins();
+        let mut ncm = std::fs::File::open("./tests/test.ncm").unwrap();
+        let format = registry.detect(&mut ncm, None).unwrap().unwrap();
+        assert_eq!(format.name(), "ncm");
+
+        let mut file = Cursor::new(vec![0u8; 100]);
+        let format = registry.detect(&mut file, Some("a.MFLAC")).unwrap();
+        assert_eq!(format.unwrap().name(), "mflac");
+        assert!(registry.detect(&mut file, Some("a.txt")).unwrap().is_none());
+
+        let mut file = Cursor::new(b"audio\x04\0\0\0QTag".to_vec());
+        let format = registry.detect(&mut file, None).unwrap();
+        assert_eq!(format.unwrap().name(), "qmc2");
+
+        let mut file = b"audio".to_vec();
+        file.extend(b"ZWtleQ==");
+        file.extend(8u32.to_le_bytes());
+        let format = registry.detect(&mut Cursor::new(file), None).unwrap();
+        assert_eq!(format.unwrap().name(), "qmc2");
+
+        let mut file = vec![0xFFu8; 200];
+        file.extend(0x200u32.to_le_bytes());
+        let format = registry.detect(&mut Cursor::new(file), None).unwrap();
+        assert!(format.is_none());
+
+        let mut file = b"audio".to_vec();
+        file.extend([0x12, 0x34, 0x56, 0x78]);
+        file.extend(4u32.to_le_bytes());
+        let format = registry.detect(&mut Cursor::new(file), None).unwrap();
+        assert!(format.is_none());
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = FormatRegistry::with_builtins();
+        registry.register(Format::new(
+            "xor",
+            vec![
+                Detector::Magic {
+                    offset: 2,
+                    magic: b"XR".to_vec(),
+                },
+                Detector::extension("xor"),
+            ],
+            |r| Ok(Box::new(XorDump(r))),
+        ));
+        // Take over an extension of a built-in format
+        registry.register(Format::new(
+            "plain",
+            vec![Detector::extension("qmc0")],
+            |r| {
+                Ok(Box::new(PlainDump {
+                    reader: r,
+                    format: MediaFormat::ID3v2,
+                }))
+            },
+        ));
+        assert_eq!(registry.formats().next().unwrap().name(), "xor");
+
+        let file = vec![0x33, 0x19, b'X', b'R'];
+        let mut dump = registry.open(Cursor::new(file), Some("song.bin")).unwrap();
+        assert!(matches!(dump.format().unwrap(), MediaFormat::fLaC));
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(&res[..2], b"fL");
+
+        let mut dump = registry
+            .open(Cursor::new(b"ID3".to_vec()), Some("a.qmc0"))
+            .unwrap();
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, b"ID3");
+    }
+}
@@ -0,0 +1,203 @@
+use crate::decryptor::Decryptor;
+use crate::error::{DumpResult, Error};
+use crate::MediaFormat;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub(crate) const MAGIC: &[u8; 4] = b"ifmt";
+const SEPARATOR: &[u8; 4] = &[0xFE, 0xFE, 0xFE, 0xFE];
+
+const HEADER_LEN: u64 = 0x10;
+
+/// Decoder for Xiami `.xm` files.
+///
+/// The header is `["ifmt"][format tag][0xFEFEFEFE][offset: u24 LE][key: u8]`.
+/// Audio bytes before the offset are plain, the rest are decrypted as
+/// `!(b - key)`.
+pub struct XmDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    key: u8,
+    encrypt_start: usize,
+    format_tag: [u8; 4],
+}
+
+impl<R: Read + Seek> XmDump<R> {
+    pub fn from_reader(mut reader: R) -> DumpResult<Self> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| Error::FormatError)?;
+
+        if &header[..4] != MAGIC || &header[8..12] != SEPARATOR {
+            return Err(Error::FormatError);
+        }
+
+        let mut format_tag = [0u8; 4];
+        format_tag.copy_from_slice(&header[4..8]);
+        let encrypt_start = u32::from_le_bytes([header[12], header[13], header[14], 0]) as usize;
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            key: header[15],
+            encrypt_start,
+            format_tag,
+        })
+    }
+
+    /// The raw format tag of the header, e.g. `b"FLAC"` or `b" MP3"`.
+    pub fn get_format_tag(&self) -> &[u8; 4] {
+        &self.format_tag
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        match &self.format_tag {
+            b" WAV" => MediaFormat::WAV,
+            b"FLAC" => MediaFormat::fLaC,
+            b" MP3" => MediaFormat::ID3v2,
+            b" A4M" => MediaFormat::M4A,
+            _ => MediaFormat::Unsupported,
+        }
+    }
+
+    /// Offset of the first encrypted byte, relative to the audio data.
+    pub fn get_encrypt_start(&self) -> usize {
+        self.encrypt_start
+    }
+
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.reader.seek(SeekFrom::Start(HEADER_LEN))?;
+        self.cursor = 0;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for XmDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        let skip = self.encrypt_start.saturating_sub(self.cursor).min(size);
+        for byte in buf[skip..size].iter_mut() {
+            *byte = !byte.wrapping_sub(self.key);
+        }
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for XmDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => SeekFrom::Start(HEADER_LEN + p),
+            p => p,
+        };
+        let new_pos = self.reader.seek(pos)?;
+
+        if new_pos < HEADER_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked to XM illegal area",
+            ));
+        }
+
+        self.cursor = (new_pos - HEADER_LEN) as usize;
+        Ok(self.cursor as u64)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for XmDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.get_format())
+    }
+
+    fn move_to_start(&mut self) -> std::io::Result<()> {
+        XmDump::move_to_start(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{XmDump, MAGIC, SEPARATOR};
+    use crate::MediaFormat;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    fn build_file(tag: &[u8; 4], offset: u32, key: u8, audio: &[u8]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend(tag);
+        file.extend(SEPARATOR);
+        file.extend(&offset.to_le_bytes()[..3]);
+        file.push(key);
+        file.extend(audio.iter().enumerate().map(|(i, &b)| {
+            if i < offset as usize {
+                b
+            } else {
+                (!b).wrapping_add(key)
+            }
+        }));
+        file
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let audio: Vec<u8> = b"fLaC"
+            .iter()
+            .copied()
+            .chain((0..1000u32).map(|n| (n * 7) as u8))
+            .collect();
+        let file = build_file(b"FLAC", 0x123, 0x5A, &audio);
+        assert_eq!(file[0x10..0x10 + 0x123], audio[..0x123]);
+
+        let mut dump = XmDump::from_reader(Cursor::new(file)).unwrap();
+        assert_eq!(dump.get_encrypt_start(), 0x123);
+        assert_eq!(dump.get_format(), MediaFormat::fLaC);
+
+        let mut res = vec![];
+        dump.read_to_end(&mut res).unwrap();
+        assert_eq!(res, audio);
+
+        dump.seek(SeekFrom::Start(0x120)).unwrap();
+        let mut res = [0u8; 8];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(res[..], audio[0x120..0x128]);
+    }
+
+    #[test]
+    fn test_decrypt_known_answer() {
+        let mut file = b"ifmt MP3\xFE\xFE\xFE\xFE\x02\x00\x01\xA7".to_vec();
+        file.extend((0..0x10010u32).map(|n| (n * 5 + 3) as u8));
+
+        let mut dump = XmDump::from_reader(Cursor::new(file)).unwrap();
+        assert_eq!(dump.get_encrypt_start(), 0x10002);
+        dump.seek(SeekFrom::Start(0xFFF8)).unwrap();
+        let mut res = [0u8; 16];
+        dump.read_exact(&mut res).unwrap();
+        assert_eq!(
+            res,
+            [
+                0xDB, 0xE0, 0xE5, 0xEA, 0xEF, 0xF4, 0xF9, 0xFE, 0x03, 0x08, 0x99, 0x94, 0x8F, 0x8A,
+                0x85, 0x80
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        for (tag, expected) in [
+            (b" WAV", MediaFormat::WAV),
+            (b" MP3", MediaFormat::ID3v2),
+            (b" A4M", MediaFormat::M4A),
+            (b"OGG ", MediaFormat::Unsupported),
+        ] {
+            let dump = XmDump::from_reader(Cursor::new(build_file(tag, 0, 1, &[0; 4]))).unwrap();
+            assert_eq!(dump.get_format_tag(), tag);
+            assert_eq!(dump.get_format(), expected);
+        }
+
+        assert!(XmDump::from_reader(Cursor::new(b"ifmtFLAC\0\0\0\0\0\0\0\0".to_vec())).is_err());
+    }
+}
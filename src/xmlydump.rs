@@ -0,0 +1,257 @@
+use crate::decryptor::Decryptor;
+use crate::error::{DumpResult, Error};
+use crate::MediaFormat;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const HEADER_LEN: usize = 1024;
+
+const X2M_KEY: &[u8] = b"xmly";
+const X3M_KEY: &[u8] = b"3989d111aad5613940f4fc44b639b292";
+
+/// Scramble table and content key of a Ximalaya file type.
+#[derive(Clone)]
+pub struct XmlyKey {
+    table: Vec<u16>,
+    key: Vec<u8>,
+}
+
+impl XmlyKey {
+    /// `table[i]` is the position in the encrypted header of the i-th
+    /// restored byte.
+    pub fn new(table: Vec<u16>, key: Vec<u8>) -> DumpResult<Self> {
+        let mut seen = [false; HEADER_LEN];
+        let permutation = table.len() == HEADER_LEN
+            && table.iter().all(|&i| {
+                (i as usize) < HEADER_LEN && !std::mem::replace(&mut seen[i as usize], true)
+            });
+        if !permutation || key.is_empty() {
+            return Err(Error::KeyLoadError);
+        }
+        Ok(Self { table, key })
+    }
+
+    pub fn x2m() -> Self {
+        Self {
+            table: build_scramble_table(0.615243, 3.837465),
+            key: X2M_KEY.to_vec(),
+        }
+    }
+
+    pub fn x3m() -> Self {
+        Self {
+            table: build_scramble_table(0.726354, 3.948264),
+            key: X3M_KEY.to_vec(),
+        }
+    }
+}
+
+/// Iterate the logistic map `x = mul * x * (1 - x)` and rank the values:
+/// the table lists the indices of the sequence in ascending order of value.
+fn build_scramble_table(init: f64, mul: f64) -> Vec<u16> {
+    let mut x = init;
+    let mut seq = Vec::with_capacity(HEADER_LEN);
+    for _ in 0..HEADER_LEN {
+        seq.push(x);
+        x = mul * x * (1.0 - x);
+    }
+
+    let mut table: Vec<u16> = (0..HEADER_LEN as u16).collect();
+    table.sort_by(|&a, &b| seq[a as usize].total_cmp(&seq[b as usize]));
+    table
+}
+
+/// Decoder for Ximalaya `.x2m`/`.x3m` files. Only the first 1024 bytes are
+/// scrambled, the rest of the file is plain audio.
+pub struct XmlyDump<R: Read> {
+    reader: R,
+    cursor: usize,
+    header: [u8; HEADER_LEN],
+    format: MediaFormat,
+}
+
+impl<R: Read + Seek> XmlyDump<R> {
+    pub fn from_reader(mut reader: R, key: &XmlyKey) -> DumpResult<Self> {
+        let mut encrypted = [0u8; HEADER_LEN];
+        reader
+            .read_exact(&mut encrypted)
+            .map_err(|_| Error::FormatError)?;
+
+        let mut header = [0u8; HEADER_LEN];
+        for (i, (byte, &index)) in header.iter_mut().zip(&key.table).enumerate() {
+            *byte = encrypted[index as usize] ^ key.key[i % key.key.len()];
+        }
+
+        Ok(Self {
+            reader,
+            cursor: 0,
+            header,
+            format: MediaFormat::sniff(&header),
+        })
+    }
+
+    pub fn get_format(&self) -> MediaFormat {
+        self.format
+    }
+
+    pub fn move_to_start(&mut self) -> std::io::Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    pub fn write_to(&mut self, writer: &mut impl Write) -> DumpResult<()> {
+        self.move_to_start()?;
+        std::io::copy(self, writer)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for XmlyDump<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cursor < HEADER_LEN {
+            let size = buf.len().min(HEADER_LEN - self.cursor);
+            buf[..size].copy_from_slice(&self.header[self.cursor..self.cursor + size]);
+            self.cursor += size;
+            return Ok(size);
+        }
+
+        let size = self.reader.read(buf)?;
+        self.cursor += size;
+        Ok(size)
+    }
+}
+
+impl<R: Read + Seek> Seek for XmlyDump<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Current(d) => (self.cursor as u64).checked_add_signed(d),
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(_) => Some(self.reader.seek(pos)?),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Seeked before the start of Ximalaya audio",
+            )
+        })?;
+
+        self.reader
+            .seek(SeekFrom::Start(new_pos.max(HEADER_LEN as u64)))?;
+        self.cursor = new_pos as usize;
+        Ok(new_pos)
+    }
+}
+
+impl<R: Read + Seek> Decryptor for XmlyDump<R> {
+    fn declared_format(&mut self) -> DumpResult<MediaFormat> {
+        Ok(self.get_format())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{XmlyDump, XmlyKey, HEADER_LEN};
+    use crate::MediaFormat;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    fn scramble(key: &XmlyKey, audio: &[u8]) -> Vec<u8> {
+        let mut file = audio.to_vec();
+        for (i, &index) in key.table.iter().enumerate() {
+            file[index as usize] = audio[i] ^ key.key[i % key.key.len()];
+        }
+        file
+    }
+
+    fn audio(magic: &[u8]) -> Vec<u8> {
+        let mut audio = magic.to_vec();
+        audio.extend((0..3000u32).map(|n| (n % 253) as u8));
+        audio
+    }
+
+    #[test]
+    fn test_scramble_table() {
+        for key in [XmlyKey::x2m(), XmlyKey::x3m()] {
+            assert!(XmlyKey::new(key.table.clone(), key.key.clone()).is_ok());
+        }
+        assert!(XmlyKey::new(vec![0; HEADER_LEN], b"xmly".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let m4a = audio(b"\0\0\0\x20ftypM4A ");
+        let mp3 = audio(b"ID3\x03");
+        for (key, audio) in [(XmlyKey::x2m(), &m4a), (XmlyKey::x3m(), &mp3)] {
+            let file = scramble(&key, audio);
+            assert_ne!(&file[..HEADER_LEN], &audio[..HEADER_LEN]);
+
+            let mut dump = XmlyDump::from_reader(Cursor::new(file), &key).unwrap();
+            let mut res = vec![];
+            dump.read_to_end(&mut res).unwrap();
+            assert_eq!(&res, audio);
+
+            dump.seek(SeekFrom::Start(1000)).unwrap();
+            let mut res = [0u8; 48];
+            dump.read_exact(&mut res).unwrap();
+            assert_eq!(res[..], audio[1000..1048]);
+        }
+
+        let dump = XmlyDump::from_reader(
+            Cursor::new(scramble(&XmlyKey::x2m(), &m4a)),
+            &XmlyKey::x2m(),
+        );
+        assert!(matches!(dump.unwrap().get_format(), MediaFormat::M4A));
+        let dump = XmlyDump::from_reader(
+            Cursor::new(scramble(&XmlyKey::x3m(), &mp3)),
+            &XmlyKey::x3m(),
+        );
+        assert!(matches!(dump.unwrap().get_format(), MediaFormat::ID3v2));
+    }
+
+    #[test]
+    fn test_known_answer() {
+        let cases: [(XmlyKey, [u16; 16], usize, [u8; 16]); 2] = [
+            (
+                XmlyKey::x2m(),
+                [
+                    0x00C, 0x012, 0x018, 0x01E, 0x024, 0x02A, 0x030, 0x036, 0x03C, 0x042, 0x048,
+                    0x04E, 0x054, 0x05A, 0x060, 0x066,
+                ],
+                275176377,
+                [
+                    0x5C, 0x5B, 0x24, 0x23, 0x14, 0x13, 0xFC, 0xDB, 0xCC, 0xAB, 0xB4, 0x93, 0x84,
+                    0x63, 0x4C, 0x4B,
+                ],
+            ),
+            (
+                XmlyKey::x3m(),
+                [
+                    0x2BA, 0x090, 0x084, 0x212, 0x30B, 0x302, 0x2B1, 0x10D, 0x04A, 0x3B1, 0x0DF,
+                    0x3C3, 0x31C, 0x192, 0x15F, 0x176,
+                ],
+                269664665,
+                [
+                    0x1D, 0x89, 0xB4, 0x0F, 0x45, 0x37, 0x22, 0x16, 0xBF, 0x72, 0xF9, 0x7C, 0x62,
+                    0x87, 0x2E, 0x5B,
+                ],
+            ),
+        ];
+
+        for (key, head, weighted, expected) in cases {
+            // The x2m sequence settles into a cycle, so most of its values
+            // tie and the order of equal values decides the table
+            assert_eq!(key.table[..16], head);
+            let sum: usize = key
+                .table
+                .iter()
+                .enumerate()
+                .map(|(i, &t)| i * t as usize)
+                .sum();
+            assert_eq!(sum, weighted);
+
+            let file: Vec<u8> = (0..HEADER_LEN + 16).map(|n| (n * 3) as u8).collect();
+            let mut dump = XmlyDump::from_reader(Cursor::new(file), &key).unwrap();
+            let mut res = [0u8; 16];
+            dump.read_exact(&mut res).unwrap();
+            assert_eq!(res, expected);
+        }
+    }
+}